@@ -0,0 +1,478 @@
+//! Contains functionality for parsing ELF files
+use std::convert::TryFrom;
+use std::path::Path;
+
+use anyhow::Context;
+use filebuffer::FileBuffer;
+
+use crate::symsrv::{ElfInfo, SymFileInfo};
+
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+
+const PT_NOTE: u32 = 4;
+const SHT_NOTE: u32 = 7;
+const SHT_NOBITS: u32 = 8;
+
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// The file name symbol servers use for the debug file of an ELF binary.
+pub const DEBUG_FILE_NAME: &str = "_.debug";
+
+/// A `.gnu_debuglink` section, naming the separate debug file of a binary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugLink {
+    pub file: String,
+    pub crc: u32,
+}
+
+/// Debug information relevant to a symbol server extracted from an ELF file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElfDebugInfo {
+    /// The contents of the `NT_GNU_BUILD_ID` note, if any.
+    pub build_id: Option<Vec<u8>>,
+    /// The separate debug file named by `.gnu_debuglink`, if any.
+    pub debuglink: Option<DebugLink>,
+}
+
+impl ElfDebugInfo {
+    /// Parse the ELF file at `filename`.
+    pub fn parse(filename: &Path) -> anyhow::Result<Self> {
+        let buf = FileBuffer::open(filename)?;
+        Self::from_bytes(&buf)
+    }
+
+    /// Parse an ELF file held in memory.
+    pub fn from_bytes(buf: &[u8]) -> anyhow::Result<Self> {
+        let elf = Elf::new(buf)?;
+
+        let mut build_id = None;
+        let mut debuglink = None;
+
+        /* Prefer the section headers, which survive in separate debug files */
+        let sections = elf.sections()?;
+        let names = elf.section_names(&sections);
+        for sh in &sections {
+            if sh.typ == SHT_NOBITS {
+                continue;
+            }
+
+            if sh.typ == SHT_NOTE && build_id.is_none() {
+                build_id = elf.find_build_id(sh.offset, sh.size);
+            } else if section_name(names, sh) == Some(b".gnu_debuglink".as_ref()) {
+                debuglink = elf.read_debuglink(sh.offset, sh.size);
+            }
+        }
+
+        /* Fall back to the program headers for binaries without sections */
+        if build_id.is_none() {
+            for ph in elf.segments()? {
+                if ph.typ == PT_NOTE {
+                    build_id = elf.find_build_id(ph.offset, ph.size);
+                    if build_id.is_some() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            build_id,
+            debuglink,
+        })
+    }
+
+    /// The symbol server key for the binary itself, stored under its own name.
+    pub fn exe_info(&self) -> Option<SymFileInfo> {
+        self.build_id.as_ref().map(|id| {
+            SymFileInfo::Elf(ElfInfo {
+                build_id: id.clone(),
+                debug: false,
+            })
+        })
+    }
+
+    /// The name and symbol server key of the binary's debug file.
+    pub fn debug_file(&self) -> Option<(&'static str, SymFileInfo)> {
+        self.build_id.as_ref().map(|id| {
+            (
+                DEBUG_FILE_NAME,
+                SymFileInfo::Elf(ElfInfo {
+                    build_id: id.clone(),
+                    debug: true,
+                }),
+            )
+        })
+    }
+}
+
+/// A section or program header, reduced to the fields we care about.
+struct Header {
+    name: u32,
+    typ: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// Look up the name of a section in the section header string table.
+fn section_name<'a>(names: Option<&'a [u8]>, sh: &Header) -> Option<&'a [u8]> {
+    let name = names?.get(sh.name as usize..)?;
+    name.split(|&b| b == 0).next()
+}
+
+/// A minimal view of an ELF file of either class and byte order.
+struct Elf<'a> {
+    buf: &'a [u8],
+    is64: bool,
+    big_endian: bool,
+}
+
+impl<'a> Elf<'a> {
+    fn new(buf: &'a [u8]) -> anyhow::Result<Self> {
+        if buf.get(..4) != Some(b"\x7fELF".as_ref()) {
+            anyhow::bail!("No ELF header present");
+        }
+
+        let is64 = match buf.get(4) {
+            Some(&ELFCLASS32) => false,
+            Some(&ELFCLASS64) => true,
+            _ => anyhow::bail!("Unsupported ELF class"),
+        };
+        let big_endian = match buf.get(5) {
+            Some(&ELFDATA2LSB) => false,
+            Some(&ELFDATA2MSB) => true,
+            _ => anyhow::bail!("Unsupported ELF byte order"),
+        };
+
+        Ok(Self {
+            buf,
+            is64,
+            big_endian,
+        })
+    }
+
+    fn bytes(&self, offset: u64, len: u64) -> Option<&'a [u8]> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        self.buf.get(start..end)
+    }
+
+    fn u16(&self, offset: u64) -> anyhow::Result<u16> {
+        let b = self.bytes(offset, 2).context("Truncated ELF file")?;
+        let b = [b[0], b[1]];
+        Ok(if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    fn u32(&self, offset: u64) -> anyhow::Result<u32> {
+        let b = self.bytes(offset, 4).context("Truncated ELF file")?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+
+    fn u64(&self, offset: u64) -> anyhow::Result<u64> {
+        let hi_lo = (self.u32(offset)? as u64, self.u32(offset + 4)? as u64);
+        Ok(if self.big_endian {
+            hi_lo.0 << 32 | hi_lo.1
+        } else {
+            hi_lo.1 << 32 | hi_lo.0
+        })
+    }
+
+    /// Read a class-sized word (`Elf32_Off` or `Elf64_Off`).
+    fn word(&self, offset: u64) -> anyhow::Result<u64> {
+        if self.is64 {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+
+    /// Read a table of `count` headers of `entsize` bytes each at `offset`.
+    fn table(
+        &self,
+        offset: u64,
+        entsize: u16,
+        count: u16,
+        parse: impl Fn(u64) -> anyhow::Result<Header>,
+    ) -> anyhow::Result<Vec<Header>> {
+        // N.B: Checking the table lies within the file keeps the offsets below from overflowing.
+        self.bytes(offset, count as u64 * entsize as u64)
+            .context("Truncated ELF file")?;
+
+        (0..count as u64)
+            .map(|i| parse(offset + i * entsize as u64))
+            .collect()
+    }
+
+    fn sections(&self) -> anyhow::Result<Vec<Header>> {
+        let (shoff, shentsize, shnum) = if self.is64 {
+            (self.u64(40)?, self.u16(58)?, self.u16(60)?)
+        } else {
+            (self.u32(32)? as u64, self.u16(46)?, self.u16(48)?)
+        };
+        if shoff == 0 {
+            return Ok(Vec::new());
+        }
+
+        let (offset_at, size_at) = if self.is64 { (24, 32) } else { (16, 20) };
+        self.table(shoff, shentsize, shnum, |o| {
+            Ok(Header {
+                name: self.u32(o)?,
+                typ: self.u32(o + 4)?,
+                offset: self.word(o + offset_at)?,
+                size: self.word(o + size_at)?,
+            })
+        })
+    }
+
+    fn segments(&self) -> anyhow::Result<Vec<Header>> {
+        let (phoff, phentsize, phnum) = if self.is64 {
+            (self.u64(32)?, self.u16(54)?, self.u16(56)?)
+        } else {
+            (self.u32(28)? as u64, self.u16(42)?, self.u16(44)?)
+        };
+        if phoff == 0 {
+            return Ok(Vec::new());
+        }
+
+        let (offset_at, size_at) = if self.is64 { (8, 32) } else { (4, 16) };
+        self.table(phoff, phentsize, phnum, |o| {
+            Ok(Header {
+                name: 0,
+                typ: self.u32(o)?,
+                offset: self.word(o + offset_at)?,
+                size: self.word(o + size_at)?,
+            })
+        })
+    }
+
+    /// Find the section header string table.
+    fn section_names(&self, sections: &[Header]) -> Option<&'a [u8]> {
+        let shstrndx = self.u16(if self.is64 { 62 } else { 50 }).ok()?;
+        let strtab = sections.get(shstrndx as usize)?;
+
+        self.bytes(strtab.offset, strtab.size)
+    }
+
+    /// Walk the notes in `offset..offset + size`, looking for a GNU build-id.
+    fn find_build_id(&self, offset: u64, size: u64) -> Option<Vec<u8>> {
+        let align4 = |n: u64| (n + 3) & !3;
+        let end = offset.checked_add(size)?;
+
+        let mut pos = offset;
+        while pos.checked_add(12)? <= end {
+            let namesz = self.u32(pos).ok()? as u64;
+            let descsz = self.u32(pos + 4).ok()? as u64;
+            let typ = self.u32(pos + 8).ok()?;
+
+            let name = self.bytes(pos + 12, namesz)?;
+            let desc_pos = (pos + 12).checked_add(align4(namesz))?;
+            if typ == NT_GNU_BUILD_ID && name == b"GNU\0" {
+                return self.bytes(desc_pos, descsz).map(<[u8]>::to_vec);
+            }
+
+            pos = desc_pos.checked_add(align4(descsz))?;
+        }
+
+        None
+    }
+
+    /// Parse a `.gnu_debuglink` section: a null terminated file name, padded
+    /// to 4 bytes, followed by the CRC32 of the debug file.
+    fn read_debuglink(&self, offset: u64, size: u64) -> Option<DebugLink> {
+        let data = self.bytes(offset, size)?;
+        let len = data.iter().position(|&b| b == 0)?;
+        let crc_pos = (len + 1 + 3) & !3;
+        let crc = data.get(crc_pos..crc_pos.checked_add(4)?)?;
+        let crc = [crc[0], crc[1], crc[2], crc[3]];
+
+        Some(DebugLink {
+            file: std::str::from_utf8(&data[..len]).ok()?.to_string(),
+            crc: if self.big_endian {
+                u32::from_be_bytes(crc)
+            } else {
+                u32::from_le_bytes(crc)
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BUILD_ID: [u8; 20] = [
+        0x15, 0xdf, 0xff, 0x32, 0x39, 0xaa, 0x7c, 0x3b, 0x16, 0xa7, 0x1e, 0x6b, 0x2e, 0x3b, 0x6e,
+        0x40, 0x09, 0xda, 0xb9, 0x98,
+    ];
+
+    /// Build a GNU build-id note in the given byte order.
+    fn build_id_note(be: bool) -> Vec<u8> {
+        let w = |v: u32| {
+            if be {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+
+        let mut note = Vec::new();
+        note.extend_from_slice(&w(4));
+        note.extend_from_slice(&w(BUILD_ID.len() as u32));
+        note.extend_from_slice(&w(NT_GNU_BUILD_ID));
+        note.extend_from_slice(b"GNU\0");
+        note.extend_from_slice(&BUILD_ID);
+        note
+    }
+
+    /// Build a little-endian ELF64 file with a build-id note section, a
+    /// `.gnu_debuglink` section and a section name table, but no program
+    /// headers. The section headers follow the section data.
+    fn build_elf64() -> Vec<u8> {
+        let note = build_id_note(false);
+        let mut debuglink = b"ls.debug\0\0\0\0".to_vec();
+        debuglink.extend_from_slice(&0xdeadbeefu32.to_le_bytes());
+        let shstrtab = b"\0.note.gnu.build-id\0.gnu_debuglink\0.shstrtab\0".to_vec();
+
+        let mut elf = vec![0u8; 64];
+        elf[..4].copy_from_slice(b"\x7fELF");
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+
+        let mut offsets = Vec::new();
+        for data in [&note, &debuglink, &shstrtab] {
+            offsets.push(elf.len() as u64);
+            elf.extend_from_slice(data);
+        }
+
+        let shoff = elf.len() as u64;
+        elf[40..48].copy_from_slice(&shoff.to_le_bytes());
+        elf[58..60].copy_from_slice(&64u16.to_le_bytes());
+        elf[60..62].copy_from_slice(&4u16.to_le_bytes());
+        elf[62..64].copy_from_slice(&3u16.to_le_bytes());
+
+        let sections = [
+            (0, 0, 0, 0),
+            (1, SHT_NOTE, offsets[0], note.len()),
+            (20, 1, offsets[1], debuglink.len()),
+            (35, 3, offsets[2], shstrtab.len()),
+        ];
+        for (name, typ, offset, size) in sections {
+            let mut sh = [0u8; 64];
+            sh[0..4].copy_from_slice(&(name as u32).to_le_bytes());
+            sh[4..8].copy_from_slice(&typ.to_le_bytes());
+            sh[24..32].copy_from_slice(&offset.to_le_bytes());
+            sh[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+            elf.extend_from_slice(&sh);
+        }
+
+        elf
+    }
+
+    /// Build a big-endian ELF32 file with only a `PT_NOTE` program header.
+    fn build_elf32_be() -> Vec<u8> {
+        let note = build_id_note(true);
+
+        let mut elf = vec![0u8; 52];
+        elf[..4].copy_from_slice(b"\x7fELF");
+        elf[4] = ELFCLASS32;
+        elf[5] = ELFDATA2MSB;
+        elf[28..32].copy_from_slice(&52u32.to_be_bytes());
+        elf[42..44].copy_from_slice(&32u16.to_be_bytes());
+        elf[44..46].copy_from_slice(&1u16.to_be_bytes());
+
+        let mut ph = [0u8; 32];
+        ph[0..4].copy_from_slice(&PT_NOTE.to_be_bytes());
+        ph[4..8].copy_from_slice(&(52u32 + 32).to_be_bytes());
+        ph[16..20].copy_from_slice(&(note.len() as u32).to_be_bytes());
+        elf.extend_from_slice(&ph);
+        elf.extend_from_slice(&note);
+
+        elf
+    }
+
+    #[test]
+    fn parse_elf64_sections() {
+        let info = ElfDebugInfo::from_bytes(&build_elf64()).unwrap();
+
+        assert_eq!(info.build_id.as_deref(), Some(&BUILD_ID[..]));
+        assert_eq!(
+            info.debuglink,
+            Some(DebugLink {
+                file: "ls.debug".to_string(),
+                crc: 0xdeadbeef,
+            })
+        );
+
+        let (name, key) = info.debug_file().unwrap();
+        assert_eq!(name, "_.debug");
+        assert_eq!(
+            key.to_string(),
+            "elf-buildid-sym-15dfff3239aa7c3b16a71e6b2e3b6e4009dab998"
+        );
+        assert_eq!(
+            info.exe_info().unwrap().to_string(),
+            "elf-buildid-15dfff3239aa7c3b16a71e6b2e3b6e4009dab998"
+        );
+    }
+
+    #[test]
+    fn parse_elf32_be_segments() {
+        let info = ElfDebugInfo::from_bytes(&build_elf32_be()).unwrap();
+
+        assert_eq!(info.build_id.as_deref(), Some(&BUILD_ID[..]));
+        assert_eq!(info.debuglink, None);
+    }
+
+    #[test]
+    fn parse_not_elf() {
+        assert!(ElfDebugInfo::from_bytes(b"MZ\x90\x00").is_err());
+        assert!(ElfDebugInfo::from_bytes(b"\x7fELF").is_err());
+
+        let mut truncated = build_elf64();
+        truncated.truncate(100);
+        assert!(ElfDebugInfo::from_bytes(&truncated).is_err());
+    }
+
+    #[test]
+    fn parse_bad_offsets() {
+        let elf = build_elf64();
+        let shoff = elf.len() - 4 * 64;
+
+        // A note section at the very end of the address space.
+        let mut bad_note = elf.clone();
+        let note_offset = shoff + 64 + 24;
+        bad_note[note_offset..note_offset + 8].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        let info = ElfDebugInfo::from_bytes(&bad_note).unwrap();
+        assert_eq!(info.build_id, None);
+
+        // A `.gnu_debuglink` section too short to hold its CRC, and one
+        // running past the end of the file.
+        let link_size = shoff + 2 * 64 + 32;
+        let mut short_link = elf.clone();
+        short_link[link_size..link_size + 8].copy_from_slice(&10u64.to_le_bytes());
+        let info = ElfDebugInfo::from_bytes(&short_link).unwrap();
+        assert_eq!(info.build_id.as_deref(), Some(&BUILD_ID[..]));
+        assert_eq!(info.debuglink, None);
+
+        let mut long_link = elf.clone();
+        long_link[link_size..link_size + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let info = ElfDebugInfo::from_bytes(&long_link).unwrap();
+        assert_eq!(info.debuglink, None);
+
+        // A section header table at the very end of the address space.
+        let mut bad_table = elf;
+        bad_table[40..48].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        assert!(ElfDebugInfo::from_bytes(&bad_table).is_err());
+    }
+}
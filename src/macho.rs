@@ -0,0 +1,295 @@
+//! Contains functionality for parsing Mach-O files, both thin and universal
+use std::convert::TryFrom;
+use std::path::Path;
+
+use anyhow::Context;
+use filebuffer::FileBuffer;
+
+use crate::symsrv::{MachInfo, SymFileInfo};
+
+const MH_MAGIC: u32 = 0xfeedface;
+const MH_MAGIC_64: u32 = 0xfeedfacf;
+const MH_CIGAM: u32 = 0xcefaedfe;
+const MH_CIGAM_64: u32 = 0xcffaedfe;
+const FAT_MAGIC: u32 = 0xcafebabe;
+const FAT_MAGIC_64: u32 = 0xcafebabf;
+
+const LC_UUID: u32 = 0x1b;
+
+/// Java class files share `FAT_MAGIC`, but follow it with a class file
+/// version of at least 45 where a universal binary has its slice count.
+const MAX_FAT_ARCHS: u32 = 45;
+
+/// The file name symbol servers use for the dSYM DWARF file of a binary.
+pub const DEBUG_FILE_NAME: &str = "_.dwarf";
+
+/// Determine whether `magic`, the first four bytes of a file, denote a thin
+/// or universal Mach-O file.
+pub fn is_macho(magic: &[u8; 4]) -> bool {
+    matches!(
+        u32::from_be_bytes(*magic),
+        MH_MAGIC | MH_MAGIC_64 | MH_CIGAM | MH_CIGAM_64 | FAT_MAGIC | FAT_MAGIC_64
+    )
+}
+
+/// A single architecture of a Mach-O file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MachSlice {
+    /// The `cputype` of the slice's header.
+    pub cpu_type: u32,
+    /// The contents of the slice's `LC_UUID` load command, if any.
+    pub uuid: Option<u128>,
+}
+
+/// Debug information relevant to a symbol server extracted from a Mach-O file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MachDebugInfo {
+    /// Every slice in the file. Thin files have exactly one.
+    pub slices: Vec<MachSlice>,
+}
+
+impl MachDebugInfo {
+    /// Parse the Mach-O file at `filename`.
+    pub fn parse(filename: &Path) -> anyhow::Result<Self> {
+        let buf = FileBuffer::open(filename)?;
+        Self::from_bytes(&buf)
+    }
+
+    /// Parse a Mach-O file held in memory.
+    pub fn from_bytes(buf: &[u8]) -> anyhow::Result<Self> {
+        let magic = be_u32(buf, 0).context("No Mach-O header present")?;
+
+        let slices = match magic {
+            FAT_MAGIC | FAT_MAGIC_64 => {
+                let nfat_arch = be_u32(buf, 4).context("Truncated universal header")?;
+                if magic == FAT_MAGIC && nfat_arch >= MAX_FAT_ARCHS {
+                    anyhow::bail!("Not a universal binary (Java class file?)");
+                }
+
+                (0..nfat_arch as usize)
+                    .map(|i| {
+                        let (offset, size) = if magic == FAT_MAGIC_64 {
+                            let arch = 8 + i * 32;
+                            (be_u64(buf, arch + 8), be_u64(buf, arch + 16))
+                        } else {
+                            let arch = 8 + i * 20;
+                            (
+                                be_u32(buf, arch + 8).map(u64::from),
+                                be_u32(buf, arch + 12).map(u64::from),
+                            )
+                        };
+
+                        let slice = offset
+                            .zip(size)
+                            .and_then(|(offset, size)| {
+                                let start = usize::try_from(offset).ok()?;
+                                let end = start.checked_add(usize::try_from(size).ok()?)?;
+                                buf.get(start..end)
+                            })
+                            .context("Truncated universal binary")?;
+
+                        parse_slice(slice)
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?
+            }
+            _ => vec![parse_slice(buf)?],
+        };
+
+        Ok(Self { slices })
+    }
+
+    /// The UUIDs of every slice, without duplicates.
+    fn uuids(&self) -> impl Iterator<Item = u128> + '_ {
+        let mut seen = Vec::new();
+        self.slices.iter().filter_map(|s| s.uuid).filter(move |u| {
+            let new = !seen.contains(u);
+            seen.push(*u);
+            new
+        })
+    }
+
+    /// The symbol server keys for the binary itself, one per slice.
+    pub fn exe_infos(&self) -> impl Iterator<Item = SymFileInfo> + '_ {
+        self.uuids()
+            .map(|uuid| SymFileInfo::Mach(MachInfo { uuid, debug: false }))
+    }
+
+    /// The name and symbol server key of each slice's dSYM DWARF file.
+    pub fn debug_files(&self) -> impl Iterator<Item = (&'static str, SymFileInfo)> + '_ {
+        self.uuids().map(|uuid| {
+            (
+                DEBUG_FILE_NAME,
+                SymFileInfo::Mach(MachInfo { uuid, debug: true }),
+            )
+        })
+    }
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let b = buf.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some((be_u32(buf, offset)? as u64) << 32 | be_u32(buf, offset + 4)? as u64)
+}
+
+/// Parse a thin Mach-O file, walking its load commands for `LC_UUID`.
+fn parse_slice(buf: &[u8]) -> anyhow::Result<MachSlice> {
+    let (big_endian, header_size) = match be_u32(buf, 0) {
+        Some(MH_MAGIC) => (true, 28),
+        Some(MH_MAGIC_64) => (true, 32),
+        Some(MH_CIGAM) => (false, 28),
+        Some(MH_CIGAM_64) => (false, 32),
+        _ => anyhow::bail!("No Mach-O header present"),
+    };
+    let u32_at = |offset: usize| {
+        be_u32(buf, offset)
+            .map(|v| if big_endian { v } else { v.swap_bytes() })
+            .context("Truncated Mach-O file")
+    };
+
+    let cpu_type = u32_at(4)?;
+    let ncmds = u32_at(16)?;
+
+    let mut uuid = None;
+    let mut offset = header_size;
+    for _ in 0..ncmds {
+        let cmd = u32_at(offset)?;
+        let cmdsize = u32_at(offset + 4)? as usize;
+        if cmdsize < 8 {
+            anyhow::bail!("Invalid Mach-O load command size");
+        }
+
+        if cmd == LC_UUID {
+            let bytes = buf
+                .get(offset + 8..offset + 24)
+                .context("Truncated LC_UUID command")?;
+            let mut raw = [0u8; 16];
+            raw.copy_from_slice(bytes);
+
+            uuid = Some(u128::from_be_bytes(raw));
+            break;
+        }
+
+        offset += cmdsize;
+    }
+
+    Ok(MachSlice { cpu_type, uuid })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const UUID_X64: u128 = 0x8a4f1c2e_9b3d_3e7f_a1b2_c3d4e5f60718;
+    const UUID_ARM64: u128 = 0x1e2d3c4b_5a69_3788_96a5_b4c3d2e1f009;
+
+    /// Build a little-endian 64-bit thin Mach-O file with an `LC_SEGMENT_64`
+    /// followed by an `LC_UUID`.
+    fn build_thin(cpu_type: u32, uuid: u128) -> Vec<u8> {
+        let mut macho = Vec::new();
+        macho.extend_from_slice(&MH_MAGIC_64.to_le_bytes());
+        macho.extend_from_slice(&cpu_type.to_le_bytes());
+        macho.extend_from_slice(&0u32.to_le_bytes());
+        macho.extend_from_slice(&2u32.to_le_bytes());
+        macho.extend_from_slice(&2u32.to_le_bytes());
+        macho.extend_from_slice(&(72u32 + 24).to_le_bytes());
+        macho.extend_from_slice(&[0; 8]);
+
+        macho.extend_from_slice(&0x19u32.to_le_bytes());
+        macho.extend_from_slice(&72u32.to_le_bytes());
+        macho.extend_from_slice(&[0; 64]);
+
+        macho.extend_from_slice(&LC_UUID.to_le_bytes());
+        macho.extend_from_slice(&24u32.to_le_bytes());
+        macho.extend_from_slice(&uuid.to_be_bytes());
+        macho
+    }
+
+    /// Build a universal binary from `slices`.
+    fn build_fat(slices: &[Vec<u8>]) -> Vec<u8> {
+        let mut fat = Vec::new();
+        fat.extend_from_slice(&FAT_MAGIC.to_be_bytes());
+        fat.extend_from_slice(&(slices.len() as u32).to_be_bytes());
+
+        let mut offset = 8 + 20 * slices.len() as u32;
+        for slice in slices {
+            fat.extend_from_slice(&slice[4..8].iter().rev().copied().collect::<Vec<_>>());
+            fat.extend_from_slice(&0u32.to_be_bytes());
+            fat.extend_from_slice(&offset.to_be_bytes());
+            fat.extend_from_slice(&(slice.len() as u32).to_be_bytes());
+            fat.extend_from_slice(&0u32.to_be_bytes());
+            offset += slice.len() as u32;
+        }
+        for slice in slices {
+            fat.extend_from_slice(slice);
+        }
+
+        fat
+    }
+
+    #[test]
+    fn parse_thin() {
+        let info = MachDebugInfo::from_bytes(&build_thin(0x0100000c, UUID_ARM64)).unwrap();
+        assert_eq!(
+            info.slices,
+            vec![MachSlice {
+                cpu_type: 0x0100000c,
+                uuid: Some(UUID_ARM64),
+            }]
+        );
+
+        let (name, key) = info.debug_files().next().unwrap();
+        assert_eq!(name, "_.dwarf");
+        assert_eq!(
+            key.to_string(),
+            "mach-uuid-sym-1e2d3c4b5a69378896a5b4c3d2e1f009"
+        );
+        assert_eq!(
+            info.exe_infos().next().unwrap().to_string(),
+            "mach-uuid-1e2d3c4b5a69378896a5b4c3d2e1f009"
+        );
+    }
+
+    #[test]
+    fn parse_universal() {
+        let x64 = build_thin(0x01000007, UUID_X64);
+        let arm64 = build_thin(0x0100000c, UUID_ARM64);
+        let info = MachDebugInfo::from_bytes(&build_fat(&[x64.clone(), arm64])).unwrap();
+
+        assert_eq!(info.slices.len(), 2);
+        assert_eq!(info.slices[0].cpu_type, 0x01000007);
+        assert_eq!(
+            info.debug_files().map(|(_, k)| k).collect::<Vec<_>>(),
+            vec![
+                SymFileInfo::Mach(MachInfo {
+                    uuid: UUID_X64,
+                    debug: true,
+                }),
+                SymFileInfo::Mach(MachInfo {
+                    uuid: UUID_ARM64,
+                    debug: true,
+                }),
+            ]
+        );
+
+        // Slices sharing a UUID only produce one key.
+        let info = MachDebugInfo::from_bytes(&build_fat(&[x64.clone(), x64])).unwrap();
+        assert_eq!(info.debug_files().count(), 1);
+    }
+
+    #[test]
+    fn parse_not_macho() {
+        // A Java class file (version 52.0) shares the universal magic.
+        assert!(MachDebugInfo::from_bytes(b"\xca\xfe\xba\xbe\x00\x00\x00\x34").is_err());
+        assert!(MachDebugInfo::from_bytes(b"MZ\x90\x00").is_err());
+
+        let mut truncated = build_thin(0x01000007, UUID_X64);
+        truncated.truncate(100);
+        assert!(MachDebugInfo::from_bytes(&truncated).is_err());
+
+        assert!(is_macho(b"\xcf\xfa\xed\xfe"));
+        assert!(!is_macho(b"\x7fELF"));
+    }
+}
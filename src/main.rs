@@ -1,6 +1,6 @@
 //! This is a tiny project to be a quick alternative to symchk for generating
 //! manifests. This mimics symchk of the form `symchk /om manifest /r <path>`
-//! but only looks for MZ/PE files.
+//! but only looks for MZ/PE, ELF and Mach-O files.
 //!
 //! Due to symchk doing some weird things it can often crash or get stuck in
 //! infinite loops. Thus this is a stricter (and much faster) alternative.
@@ -13,22 +13,31 @@
 use anyhow::Context;
 use clap::{Parser, Subcommand, ValueEnum};
 use indicatif::{MultiProgress, ProgressStyle};
-use symsrv::{SymSrvList, SymSrvSpec};
+use symsrv::SymSrvList;
 
-use std::io::SeekFrom;
-use std::io::{self, Read, Seek};
+use std::collections::VecDeque;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures::{stream, Stream, StreamExt};
+use futures::{stream, stream::FuturesUnordered, Stream, StreamExt};
 use indicatif::ProgressBar;
 use tokio::{
     fs::{self, DirEntry},
     io::AsyncWriteExt,
+    sync::Semaphore,
 };
 
-use symsrv::{nonblocking::SymSrv, DownloadError, DownloadStatus, SymFileInfo};
+use symsrv::{
+    negcache::NegativeCache,
+    nonblocking::{RateLimiter, RetryPolicy, Slot, SymSrv},
+    DownloadError, DownloadStatus, ServerKind, SymFileInfo, SymSrvSpec,
+};
 
+mod elf;
+mod macho;
 mod pe;
 #[allow(dead_code)]
 mod symsrv;
@@ -72,8 +81,6 @@ fn recursive_listdir(
 }
 
 fn get_pdb_path<P: AsRef<Path>>(pdbname: P) -> anyhow::Result<PathBuf> {
-    use pdb::PDB;
-
     let file_name = Path::new(
         pdbname
             .as_ref()
@@ -81,27 +88,59 @@ fn get_pdb_path<P: AsRef<Path>>(pdbname: P) -> anyhow::Result<PathBuf> {
             .context("no filename component on path")?,
     );
 
-    let f = std::fs::File::open(&pdbname).context("failed to open file")?;
-    let mut pdb = PDB::open(f).context("failed to parse PDB")?;
+    let hash = symsrv::verify::pdb_hash(pdbname.as_ref())?;
+    Ok(file_name.join(hash).join(file_name))
+}
+
+/// Given a .NET assembly `filename`, decompress its embedded portable PDB (if
+/// any) and return it along with its relative path in a symbol store.
+fn get_embedded_pdb(filename: &Path) -> anyhow::Result<Option<(PathBuf, Vec<u8>)>> {
+    Ok(pe::extract_embedded_pdb(filename)?.map(|(cv, pdb)| {
+        let name = Path::new(cv.pdb_name());
+        (name.join(cv.sym_info().to_string()).join(name), pdb)
+    }))
+}
+
+/// The object file formats we know how to pull debug information from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ImageFormat {
+    Pe,
+    Elf,
+    MachO,
+}
 
-    // Query the GUID and age.
-    let pdbi = pdb
-        .pdb_information()
-        .context("failed to find PDB information stream")?;
-    let dbi = pdb
-        .debug_information()
-        .context("failed to find DBI stream")?;
+/// Determine the format of `filename` from its magic. Anything unrecognized
+/// is assumed to be a PE, and left for the PE parser to reject.
+fn image_format(filename: &Path) -> ImageFormat {
+    use std::io::Read;
 
-    let guid = pdbi.guid;
-    let age = dbi.age().unwrap_or(pdbi.age);
+    let mut magic = [0u8; 4];
+    if std::fs::File::open(filename)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_err()
+    {
+        return ImageFormat::Pe;
+    }
 
-    Ok(file_name
-        .join(format!("{:032X}{:x}", guid.as_u128(), age))
-        .join(file_name))
+    match &magic {
+        b"\x7fELF" => ImageFormat::Elf,
+        m if macho::is_macho(m) => ImageFormat::MachO,
+        _ => ImageFormat::Pe,
+    }
 }
 
-fn get_file_path(filename: &Path) -> anyhow::Result<String> {
-    let (_, _, pe_header, image_size, _) = pe::parse_pe(filename)?;
+/// Given a `filename`, return the relative paths of the PE, ELF or Mach-O file
+/// in a symbol store, e.g. "notepad.exe/8A9D6E1F3c000/notepad.exe".
+///
+/// Universal Mach-O binaries are stored once per slice.
+fn get_file_paths(filename: &Path) -> anyhow::Result<Vec<String>> {
+    let infos = match image_format(filename) {
+        ImageFormat::Pe => vec![pe::PeDebugInfo::parse(filename)?.exe_info()],
+        ImageFormat::Elf => vec![elf::ElfDebugInfo::parse(filename)?
+            .exe_info()
+            .context("Failed to find a build-id note")?],
+        ImageFormat::MachO => macho::MachDebugInfo::parse(filename)?.exe_infos().collect(),
+    };
 
     let filename = filename
         .file_name()
@@ -109,164 +148,70 @@ fn get_file_path(filename: &Path) -> anyhow::Result<String> {
         .to_str()
         .context("Failed to convert file name")?;
 
-    let filestr = format!(
-        "{}/{:08x}{:x}/{}",
-        filename,
-        { pe_header.timestamp },
-        image_size,
-        filename
-    );
-
-    /* For hashes
-    let filestr = format!("{},{:08x}{:x},1",
-                          filename.file_name()
-                            .unwrap().to_str().unwrap(),
-                          pe_header.timestamp,
-                          image_size);*/
-
-    Ok(filestr)
+    Ok(infos
+        .into_iter()
+        .map(|info| format!("{}/{}/{}", filename, info, filename))
+        .collect())
 }
 
-/// Given a `filename`, attempt to parse out any mention of a PDB file in it.
+/// Given a `filename`, attempt to parse out every mention of a PDB file in it.
 ///
 /// This returns success if it successfully parses the MZ, PE, finds a debug
-/// header, matches RSDS signature, and contains a valid reference to a PDB.
+/// header, and finds at least one CodeView record referencing a PDB.
 ///
-/// Returns a string which is the same representation you get from `symchk`
-/// when outputting a manifest for the PDB "<filename>,<guid><age>,1"
-fn get_pdb(filename: &Path) -> anyhow::Result<String> {
-    let (mut fd, mz_header, pe_header, _, num_tables) = pe::parse_pe(filename)?;
-
-    /* Load all the data directories into a vector */
-    let mut data_dirs = Vec::new();
-    for _ in 0..num_tables {
-        let datadir: pe::ImageDataDirectory = pe::read_struct(&mut fd)?;
-        data_dirs.push(datadir);
-    }
-
-    /* Debug directory is at offset 6, validate we have at least 7 entries */
-    if data_dirs.len() < 7 {
-        anyhow::bail!("No debug data directory");
-    }
-
-    /* Grab the debug table */
-    let debug_table = data_dirs[6];
-    if debug_table.vaddr == 0 || debug_table.size == 0 {
-        anyhow::bail!("Debug directory not present or zero sized");
-    }
-
-    /* Validate debug table size is sane */
-    let iddlen = std::mem::size_of::<pe::ImageDebugDirectory>() as u32;
-    let debug_table_ents = debug_table.size / iddlen;
-    if (debug_table.size % iddlen) != 0 || debug_table_ents == 0 {
-        anyhow::bail!("No debug entries or not mod ImageDebugDirectory");
-    }
-
-    /* Seek to where the section table should be */
-    let section_headers =
-        mz_header.new_header as u64 + 0x18 + pe_header.optional_header_size as u64;
-    if fd.seek(SeekFrom::Start(section_headers))? != section_headers {
-        anyhow::bail!("Failed to seek to section table");
+/// Each entry has the same representation you get from `symchk` when
+/// outputting a manifest for the PDB "<filename>,<guid><age>,1"
+fn get_pdb(filename: &Path) -> anyhow::Result<Vec<ManifestEntry>> {
+    let info = pe::PeDebugInfo::parse(filename)?;
+
+    let entries = info
+        .pdbs()
+        .map(|(name, info)| ManifestEntry::new(name, &info))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        anyhow::bail!("Failed to find a codeview directory");
     }
 
-    /* Parse all the sections into a vector */
-    let mut sections = Vec::new();
-    for _ in 0..pe_header.num_sections {
-        let sechdr: pe::ImageSectionHeader = pe::read_struct(&mut fd)?;
-        sections.push(sechdr);
-    }
+    Ok(entries)
+}
 
-    let debug_raw_ptr = {
-        /* Find the section the debug table belongs to */
-        let mut debug_data = None;
-        for section in &sections {
-            /* We use raw_data_size instead of vsize as we are not loading the
-             * file and only care about raw contents in the file.
-             */
-            let secrange = section.vaddr..section.vaddr + section.raw_data_size;
-
-            /* Check if the entire debug table is contained in this sections
-             * virtual address range.
-             */
-            if secrange.contains(&{ debug_table.vaddr })
-                && secrange.contains(&(debug_table.vaddr + debug_table.size - 1))
-            {
-                debug_data = Some(debug_table.vaddr - section.vaddr + section.pointer_to_raw_data);
-                break;
-            }
-        }
+/// Given an ELF `filename`, return the manifest entry for its separate debug
+/// file, e.g. "_.debug,elf-buildid-sym-<build-id>,1".
+fn get_elf_debug(filename: &Path) -> anyhow::Result<Vec<ManifestEntry>> {
+    let info = elf::ElfDebugInfo::parse(filename)?;
+    let (name, info) = info
+        .debug_file()
+        .context("Failed to find a build-id note")?;
 
-        match debug_data {
-            Some(d) => d as u64,
-            None => anyhow::bail!("Unable to find debug data"),
-        }
-    };
+    Ok(vec![ManifestEntry::new(name, &info)])
+}
 
-    /* Seek to where the debug directories should be */
-    if fd.seek(SeekFrom::Start(debug_raw_ptr))? != debug_raw_ptr {
-        anyhow::bail!("Failed to seek to debug directories");
+/// Given a Mach-O `filename`, return the manifest entries for the dSYM DWARF
+/// file of each slice, e.g. "_.dwarf,mach-uuid-sym-<uuid>,1".
+fn get_macho_debug(filename: &Path) -> anyhow::Result<Vec<ManifestEntry>> {
+    let info = macho::MachDebugInfo::parse(filename)?;
+
+    let entries = info
+        .debug_files()
+        .map(|(name, info)| ManifestEntry::new(name, &info))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        anyhow::bail!("Failed to find an LC_UUID command");
     }
 
-    /* Look through all debug table entries for codeview entries */
-    for _ in 0..debug_table_ents {
-        let de: pe::ImageDebugDirectory = pe::read_struct(&mut fd)?;
-
-        if de.typ == pe::IMAGE_DEBUG_TYPE_CODEVIEW {
-            /* Seek to where the codeview entry should be */
-            let cvo = de.pointer_to_raw_data as u64;
-            if fd.seek(SeekFrom::Start(cvo))? != cvo {
-                anyhow::bail!("Failed to seek to codeview entry");
-            }
-
-            let cv: pe::CodeviewEntry = pe::read_struct(&mut fd)?;
-            if &cv.signature != b"RSDS" {
-                anyhow::bail!("No RSDS signature present in codeview ent");
-            }
+    Ok(entries)
+}
 
-            /* Calculate theoretical string length based on the size of the
-             * section vs the size of the header */
-            let cv_strlen = de.size_of_data as usize - std::mem::size_of_val(&cv);
-
-            /* Read in the debug path */
-            let mut dpath = vec![0u8; cv_strlen];
-            fd.read_exact(&mut dpath)?;
-
-            /* PDB strings are utf8 and null terminated, find the first null
-             * and we will split it there.
-             */
-            if let Some(null_strlen) = dpath.iter().position(|&x| x == 0) {
-                let dpath = std::str::from_utf8(&dpath[..null_strlen])?;
-
-                /* Further, since this path can be a full path, we get only
-                 * the filename component of this path.
-                 */
-                if let Some(pdbfilename) = Path::new(dpath).file_name() {
-                    /* This is the format string used by symchk.
-                     * Original is in SymChkCheckFiles()
-                     * "%s,%08X%04X%04X%02X%02X%02X%02X%02X%02X%02X%02X%x,1"
-                     */
-                    let guidstr = format!("{},{:08X}{:04X}{:04X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}{:x},1",
-                                          pdbfilename.to_str().context("Failed to get PDB filename")?,
-                                          {cv.guid_a}, {cv.guid_b}, {cv.guid_c},
-                                          {cv.guid_d[0]}, {cv.guid_d[1]},
-                                          {cv.guid_d[2]}, {cv.guid_d[3]},
-                                          {cv.guid_d[4]}, {cv.guid_d[5]},
-                                          {cv.guid_d[6]}, {cv.guid_d[7]},
-                                          {cv.age});
-                    return Ok(guidstr);
-                } else {
-                    anyhow::bail!("Could not parse file from RSDS path");
-                }
-            } else {
-                anyhow::bail!("Failed to find null terminiator in RSDS");
-            }
-        }
+/// Given a `filename`, return the manifest entries for the debug files it
+/// references, dispatching on its file format.
+fn get_debug_files(filename: &Path) -> anyhow::Result<Vec<ManifestEntry>> {
+    match image_format(filename) {
+        ImageFormat::Pe => get_pdb(filename),
+        ImageFormat::Elf => get_elf_debug(filename),
+        ImageFormat::MachO => get_macho_debug(filename),
     }
-
-    anyhow::bail!("Failed to find RSDS codeview directory")
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone)]
 struct ManifestEntry {
     /// The PDB's name
@@ -277,6 +222,22 @@ struct ManifestEntry {
     version: u32,
 }
 
+impl ManifestEntry {
+    fn new(name: &str, info: &SymFileInfo) -> Self {
+        Self {
+            name: name.to_string(),
+            hash: info.to_string(),
+            version: 1,
+        }
+    }
+}
+
+impl std::fmt::Display for ManifestEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{}", self.name, self.hash, self.version)
+    }
+}
+
 impl FromStr for ManifestEntry {
     type Err = anyhow::Error;
 
@@ -298,19 +259,91 @@ impl FromStr for ManifestEntry {
 fn connect_servers(srvstr: &str) -> anyhow::Result<Box<[SymSrv]>> {
     let srvlist = SymSrvList::from_str(srvstr).context("failed to parse server list")?;
 
-    match srvlist
+    let servers = srvlist
         .0
         .iter()
-        .map(|s| SymSrv::connect(s.clone()).map_err(|e| (s.clone(), e)))
-        .collect::<Result<Vec<_>, (SymSrvSpec, anyhow::Error)>>()
-    {
-        Ok(srv) => Ok(srv.into_boxed_slice()),
-        Err((s, e)) => Err(e.context(format!("failed to connect to server {s}"))),
-    }
+        .map(|s| {
+            SymSrv::connect(s.clone()).with_context(|| format!("failed to connect to server {s}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(servers.into_boxed_slice())
+}
+
+/// Try to download a file from `srv`, the server at index `i`, in `slot` if
+/// one was taken for it. The index is handed back with the file and result.
+async fn try_server(
+    srv: &SymSrv,
+    i: usize,
+    name: String,
+    info: SymFileInfo,
+    slot: Option<Slot>,
+    m: &MultiProgress,
+) -> (
+    usize,
+    String,
+    SymFileInfo,
+    Result<(DownloadStatus, PathBuf), DownloadError>,
+) {
+    let res = match slot {
+        Some(slot) => srv.download_file_in_slot(&name, &info, m, slot).await,
+        None => srv.download_file_progress(&name, &info, m).await,
+    };
+
+    (i, name, info, res)
 }
 
-pub async fn download_manifest(srvstr: &str, files: Vec<String>) -> anyhow::Result<()> {
-    let servers = connect_servers(srvstr)?;
+/// Options controlling how [`download_manifest`] downloads files.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Reject downloaded PDBs whose GUID and age don't match the manifest.
+    pub verify: bool,
+    /// How requests that fail transiently are retried.
+    pub retry: RetryPolicy,
+    /// How long to remember that a server doesn't have a file.
+    pub miss_ttl: Duration,
+    /// Ask servers again for files they recently didn't have.
+    pub refresh_misses: bool,
+    /// The maximum number of concurrent downloads, across all servers.
+    pub jobs: usize,
+    /// The maximum download rate across all servers, in bytes per second.
+    pub rate_limit: Option<u64>,
+}
+
+pub async fn download_manifest(
+    srvstr: &str,
+    files: Vec<String>,
+    opts: DownloadOptions,
+) -> anyhow::Result<()> {
+    let jobs = Arc::new(Semaphore::new(opts.jobs));
+    let rate_limit = opts.rate_limit.map(|r| Arc::new(RateLimiter::new(r)));
+    let servers = connect_servers(srvstr)?
+        .into_vec()
+        .into_iter()
+        .map(|s| {
+            let s = s
+                .with_verification(opts.verify)
+                .with_retry_policy(opts.retry.clone())
+                .with_job_limit(jobs.clone());
+
+            match &rate_limit {
+                Some(limit) => s.with_rate_limit(limit.clone()),
+                None => s,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Files the servers didn't have last time, kept in the nearest cache. A store made its
+    // own cache (e.g. a shared UNC path) is left alone, so without another cache there is none.
+    let cache = servers.iter().map(SymSrv::spec).find(SymSrvSpec::has_cache);
+    let misses = match cache.map(|spec| NegativeCache::load(&spec.cache_path, opts.miss_ttl)) {
+        Some(Ok(misses)) => Some(misses.with_bypass(opts.refresh_misses)),
+        Some(Err(e)) => {
+            println!("Failed to load negative cache: {:?}", e);
+            None
+        }
+        None => None,
+    };
 
     // http://patshaughnessy.net/2020/1/20/downloading-100000-files-using-async-rust
     // The following code is based off of the above blog post.
@@ -325,47 +358,122 @@ pub async fn download_manifest(srvstr: &str, files: Vec<String>) -> anyhow::Resu
             .progress_chars("█▉▊▋▌▍▎▏  "),
     );
 
-    // Set up our asynchronous code block.
-    // This block will be lazily executed when something awaits on it, such as the tokio thread pool below.
-    let queries = futures::stream::iter(
-        // Map the files vector using a closure, such that it's converted from a Vec<String>
-        // into a Vec<Result<T, E>>
-        files.into_iter().map(|line| {
-            // Take explicit references to a few variables and move them into the async block.
-            let servers = &servers;
-            let pb = pb.clone();
-            let m = &m;
-
-            async move {
-                pb.inc(1);
-
-                let e = ManifestEntry::from_str(&line).unwrap();
-                let info = SymFileInfo::RawHash(e.hash);
-
-                for srv in servers.iter() {
-                    if srv.find_file(&e.name, &info).is_some() {
-                        return Ok(DownloadStatus::AlreadyExists);
-                    }
+    let mut output = Vec::with_capacity(files.len());
+
+    // Servers that have rejected our credentials, and won't be asked again.
+    let mut rejected = vec![false; servers.len()];
+
+    // Each server has a queue of the files waiting to ask it, which start out with the first
+    // server and move on to the next one when a server doesn't have them. Free slots are handed
+    // to the servers in turn, one file each per round, so a server with a long queue doesn't
+    // crowd out the others.
+    let mut queues = servers.iter().map(|_| VecDeque::new()).collect::<Vec<_>>();
+    for line in files {
+        let e = ManifestEntry::from_str(&line).unwrap();
+        let info = SymFileInfo::RawHash(e.hash);
+        match queues.first_mut() {
+            Some(queue) => queue.push_back((e.name, info)),
+            None => output.push(Err(DownloadError::FileNotFound)),
+        }
+    }
 
-                    match srv.download_file_progress(&e.name, &info, m).await {
-                        Ok(_) => return Ok(DownloadStatus::DownloadedOk),
-                        Err(_e) => {}
+    // N.B: Files found in a cache don't need a slot, but we still bound how many files we work
+    // on at once so we don't exhaust system resources in the networking stack or filesystem.
+    let window = opts.jobs.saturating_mul(servers.len());
+    let mut in_flight = FuturesUnordered::new();
+
+    let mut done = |res| {
+        pb.inc(1);
+        output.push(res);
+    };
+
+    loop {
+        let mut started = true;
+        while started && in_flight.len() < window {
+            started = false;
+
+            for (i, srv) in servers.iter().enumerate() {
+                while in_flight.len() < window {
+                    let (name, info) = match queues[i].pop_front() {
+                        Some(file) => file,
+                        None => break,
                     };
+
+                    // Don't ask the server again for a file it recently didn't have,
+                    // unless it has since turned up in the cache.
+                    let skip = rejected[i]
+                        || (matches!(&misses, Some(m) if m.contains(&srv.spec(), &name, &info))
+                            && srv.find_file(&name, &info).is_none());
+
+                    if skip {
+                        match queues.get_mut(i + 1) {
+                            Some(next) => next.push_back((name, info)),
+                            None => done(Err(DownloadError::FileNotFound)),
+                        }
+                    } else if srv.find_file(&name, &info).is_some() {
+                        // N.B: This also brings files found in slower caches into the local one.
+                        in_flight.push(try_server(srv, i, name, info, None, &m));
+                    } else if let Some(slot) = srv.try_acquire() {
+                        in_flight.push(try_server(srv, i, name, info, Some(slot), &m));
+                        started = true;
+                        break;
+                    } else {
+                        queues[i].push_front((name, info));
+                        break;
+                    }
                 }
+            }
+        }
 
-                Err(DownloadError::FileNotFound)
+        let (i, name, info, res) = match in_flight.next().await {
+            Some(res) => res,
+            None => break,
+        };
+
+        let spec = servers[i].spec();
+        match res {
+            Ok((status, _)) => {
+                if let Some(misses) = &misses {
+                    misses.remove(&spec, &name, &info);
+                }
+                done(Ok(status));
+                continue;
+            }
+            // N.B: Only remember real misses from HTTP servers. A server error may well clear
+            // up, and looking in a directory is as cheap as looking up the miss.
+            Err(DownloadError::FileNotFound) if spec.kind != ServerKind::Filesystem => {
+                if let Some(misses) = &misses {
+                    misses.insert(&spec, &name, &info);
+                }
+            }
+            Err(DownloadError::Message(msg)) => {
+                let _ = m.println(format!("{}/{}: {}", name, info, msg));
             }
-        }),
-    )
-    .buffer_unordered(32)
-    .collect::<Vec<Result<DownloadStatus, DownloadError>>>();
+            Err(err @ DownloadError::Authentication { .. }) => {
+                // Only report this once, and stop using the server from here on.
+                if !std::mem::replace(&mut rejected[i], true) {
+                    let _ = m.println(format!("{err}; skipping this server"));
+                }
+            }
+            Err(_e) => {}
+        };
+
+        match queues.get_mut(i + 1) {
+            Some(next) => next.push_back((name, info)),
+            None => done(Err(DownloadError::FileNotFound)),
+        }
+    }
 
-    // N.B: The buffer_unordered bit above allows us to feed in 64 requests at a time to tokio.
-    // That way we don't exhaust system resources in the networking stack or filesystem.
-    let output = queries.await;
+    // Nothing is left in flight only once every file has been through its servers.
+    debug_assert!(queues.iter().all(VecDeque::is_empty));
 
     pb.finish();
 
+    // N.B: Failing to save this only costs us some requests next time.
+    if let Some(Err(e)) = misses.as_ref().map(NegativeCache::save) {
+        println!("Failed to save negative cache: {:?}", e);
+    }
+
     let mut ok = 0u64;
     let mut ok_exists = 0u64;
     let mut err = 0u64;
@@ -382,13 +490,88 @@ pub async fn download_manifest(srvstr: &str, files: Vec<String>) -> anyhow::Resu
         },
     });
 
+    for (srv, rejected) in servers.iter().zip(&rejected) {
+        if *rejected {
+            println!(
+                "Server {} rejected our credentials and was skipped",
+                srv.spec().server_url
+            );
+        }
+    }
+
     println!("{} files failed to download", err);
     println!("{} files already downloaded", ok_exists);
     println!("{} files downloaded successfully", ok);
+    println!(
+        "{} requests retried",
+        servers.iter().map(|s| s.retries()).sum::<u64>()
+    );
+    if let Some(misses) = &misses {
+        println!("{} requests skipped for recent misses", misses.hits());
+    }
 
     Ok(())
 }
 
+/// Check every PDB in the symbol cache at `cache_path` against the key it is
+/// stored under, optionally removing the ones that don't match.
+///
+/// Returns the number of PDBs that failed verification.
+async fn verify_cache(cache_path: &Path, remove: bool) -> anyhow::Result<u64> {
+    use symsrv::verify::{verify_pdb, Verification};
+
+    let mut ok = 0u64;
+    let mut skipped = 0u64;
+    let mut bad = 0u64;
+
+    let mut listing = Box::pin(recursive_listdir(cache_path));
+    while let Some(entry) = listing.next().await {
+        let path = entry.context("failed to list symbol cache")?.path();
+
+        // Only consider files laid out as "<name>/<hash>/<name>".
+        let hash = match (path.file_name(), path.parent()) {
+            (Some(name), Some(hash_dir))
+                if hash_dir.parent().and_then(Path::file_name) == Some(name) =>
+            {
+                match hash_dir.file_name().and_then(|h| h.to_str()) {
+                    Some(h) => h.to_string(),
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+
+        let p = path.clone();
+        let result = tokio::task::spawn_blocking(move || verify_pdb(&p, &hash)).await??;
+        let problem = match result {
+            Verification::Match => {
+                ok += 1;
+                continue;
+            }
+            Verification::Unsupported => {
+                skipped += 1;
+                continue;
+            }
+            Verification::Mismatch { actual } => format!("signature is {actual}"),
+            Verification::Corrupt(e) => e,
+        };
+
+        bad += 1;
+        println!("{}: {}", path.display(), problem);
+        if remove {
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                println!("Failed to remove file {:?}: {err:#}", &path);
+            }
+        }
+    }
+
+    println!("{} PDBs verified", ok);
+    println!("{} files skipped", skipped);
+    println!("{} PDBs failed verification", bad);
+
+    Ok(bad)
+}
+
 /// This tool lets you quickly download PDBs from a symbol server
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -416,19 +599,44 @@ enum Command {
     },
     /// Downloads all the PDBs specified in the manifest file
     Download {
-        /// The symbol server URL
+        /// The symbol path, e.g. `SRV*C:\Symbols*https://msdl.microsoft.com/download/symbols`
+        #[arg(env = "_NT_SYMBOL_PATH")]
         symsrv: String,
         /// The manifest path
         manifest: Option<PathBuf>,
+        /// Reject downloaded PDBs whose GUID and age don't match the manifest, keeping them
+        /// beside the cache path as `<name>.mismatch`
+        #[arg(long)]
+        verify: bool,
+        /// How many times to retry a request that failed transiently
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+        /// How many hours to remember that a server doesn't have a file
+        #[arg(long, default_value_t = 24)]
+        miss_ttl: u64,
+        /// Ask servers again for files they recently didn't have
+        #[arg(long)]
+        refresh_misses: bool,
+        /// The maximum number of concurrent downloads, across all servers
+        #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..))]
+        jobs: u32,
+        /// The maximum download rate across all servers in bytes per second, e.g. `512K` or `2M`
+        #[arg(long, value_parser = symsrv::sympath::parse_rate)]
+        limit_rate: Option<u64>,
     },
     /// Downloads a PDB file corresponding to a single PE file
     DownloadSingle {
-        /// The symbol server URL
+        /// The symbol path, e.g. `SRV*C:\Symbols*https://msdl.microsoft.com/download/symbols`
+        #[arg(env = "_NT_SYMBOL_PATH")]
         symsrv: String,
         /// The PE file path
         filepath: PathBuf,
         /// The format to print the message in
         message_format: MessageFormat,
+        /// Reject a downloaded PDB whose GUID and age don't match the PE file, keeping it
+        /// beside the cache path as `<name>.mismatch`
+        #[arg(long)]
+        verify: bool,
     },
     /// Recursively searches a directory tree and caches all PEs in the current directory in a symbol cache layout
     ///
@@ -443,9 +651,9 @@ enum Command {
     /// store/cache path. We keep it separate in this tool just to make it
     /// easier to only get PDBs if that's all you really want.
     Filestore {
-        /// The root of the directory tree to search for PEs
+        /// The root of the directory tree to search for PE, ELF and Mach-O files
         filepath: PathBuf,
-        /// The target directory to stash PEs in
+        /// The target directory to stash them in
         targetpath: PathBuf,
     },
     /// Recursively searches a directory tree and caches all PDBs in the current directory in a symbol cache layout
@@ -455,6 +663,9 @@ enum Command {
     /// same layout as symchk.exe. This is used to create a store of all PDBs
     /// which can be used by a kernel debugger to resolve symbols.
     ///
+    /// With `--embedded`, portable PDBs embedded in .NET assemblies are
+    /// decompressed into the store as well, so they never need downloading.
+    ///
     /// To use this filestore simply merge the contents in with a symbol
     /// store/cache path. We keep it separate in this tool just to make it
     /// easier to only get PDBs if that's all you really want.
@@ -463,18 +674,39 @@ enum Command {
         filepath: PathBuf,
         /// The target directory to stash PDBs in
         targetpath: PathBuf,
+        /// Also extract portable PDBs embedded in .NET assemblies
+        #[arg(long)]
+        embedded: bool,
     },
     /// Various information-related subcommands
     #[command(subcommand)]
     Info(InfoCommand),
+    /// Symbol cache maintenance subcommands
+    #[command(subcommand)]
+    Cache(CacheCommand),
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum CacheCommand {
+    /// Checks that every PDB in a symbol cache matches the GUID and age it is stored under
+    Verify {
+        /// The root of the symbol cache
+        cache_path: PathBuf,
+        /// Delete PDBs that fail verification
+        #[arg(long)]
+        remove: bool,
+    },
 }
 
 #[derive(Subcommand, Clone, Debug)]
 enum InfoCommand {
-    /// Dumps out the hash of the corresponding PDB file for a PE file
+    /// Dumps out the hash of every PDB file referenced by a PE file
     Pdbhash {
         /// The path to the PE file to dump the PDB hash for
         filepath: PathBuf,
+        /// Tag each hash with the kind of CodeView record it came from
+        #[arg(long)]
+        kind: bool,
     },
 }
 
@@ -508,10 +740,7 @@ async fn run() -> anyhow::Result<()> {
                         Ok(e) => Some(tokio::spawn(async move {
                             pb.inc(1);
 
-                            match get_pdb(&e.path()) {
-                                Ok(manifest_str) => Some(manifest_str),
-                                Err(_) => None,
-                            }
+                            get_debug_files(&e.path()).ok()
                         })),
 
                         Err(_) => None,
@@ -525,7 +754,7 @@ async fn run() -> anyhow::Result<()> {
                 .context("Failed to create output manifest file")?;
 
             for task in tasks {
-                if let Some(e) = task.await.unwrap() {
+                for e in task.await.unwrap().into_iter().flatten() {
                     output_file
                         .write(format!("{}\n", &e).as_bytes())
                         .await
@@ -533,7 +762,16 @@ async fn run() -> anyhow::Result<()> {
                 }
             }
         }
-        Command::Download { manifest, symsrv } => {
+        Command::Download {
+            manifest,
+            symsrv,
+            verify,
+            retries,
+            miss_ttl,
+            refresh_misses,
+            jobs,
+            limit_rate,
+        } => {
             /* Read the entire manifest file into a string */
             let manifest_path = manifest.unwrap_or(PathBuf::from("manifest"));
             let buf = tokio::fs::read_to_string(&manifest_path)
@@ -556,7 +794,19 @@ async fn run() -> anyhow::Result<()> {
 
             println!("Deduped manifest has {} PDBs", lines.len());
 
-            match download_manifest(&symsrv, lines).await {
+            let opts = DownloadOptions {
+                verify,
+                retry: RetryPolicy {
+                    max_attempts: retries.saturating_add(1),
+                    ..Default::default()
+                },
+                miss_ttl: Duration::from_secs(miss_ttl.saturating_mul(60 * 60)),
+                refresh_misses,
+                jobs: jobs as usize,
+                rate_limit: limit_rate,
+            };
+
+            match download_manifest(&symsrv, lines, opts).await {
                 Ok(_) => println!("Success!"),
                 Err(e) => println!("Failed: {:?}", e),
             }
@@ -565,6 +815,7 @@ async fn run() -> anyhow::Result<()> {
             symsrv,
             filepath,
             message_format,
+            verify,
         } => {
             use serde_json::json;
 
@@ -572,30 +823,30 @@ async fn run() -> anyhow::Result<()> {
                 let servers = connect_servers(&symsrv)?;
 
                 // Resolve the PDB for the executable specified.
-                let e = ManifestEntry::from_str(
-                    &get_pdb(&filepath).context("failed to resolve PDB hash")?,
-                )
-                .unwrap();
-                let info = SymFileInfo::RawHash(e.hash);
-
-                for srv in servers.iter() {
-                    let (message, path) = {
-                        if let Some(p) = srv.find_file(&e.name, &info) {
-                            ("file already cached", p)
-                        } else {
-                            let path = srv
-                                .download_file(&e.name, &info)
-                                .await
-                                .context("failed to download PDB")?;
-
-                            ("file successfully downloaded", path)
-                        }
-                    };
-
-                    return Ok((message, path));
+                let pe_info =
+                    pe::PeDebugInfo::parse(&filepath).context("failed to resolve PDB hash")?;
+                let cv = pe_info
+                    .primary()
+                    .context("failed to resolve PDB hash: no codeview record")?;
+                let (name, info) = (cv.pdb_name(), cv.sym_info());
+                let checksums = pe_info.checksums_for(cv);
+
+                let srv = match servers.first() {
+                    Some(srv) => srv,
+                    None => anyhow::bail!("no server returned the PDB file"),
+                };
+
+                let (status, path) = srv
+                    .clone()
+                    .with_verification(verify)
+                    .download_file_checked(name, &info, checksums)
+                    .await
+                    .context("failed to download PDB")?;
+
+                match status {
+                    DownloadStatus::AlreadyExists => Ok(("file already cached", path)),
+                    DownloadStatus::DownloadedOk => Ok(("file successfully downloaded", path)),
                 }
-
-                anyhow::bail!("no server returned the PDB file")
             }
             .await;
 
@@ -640,7 +891,7 @@ async fn run() -> anyhow::Result<()> {
             listing
                 .for_each(|entry| async {
                     if let Ok(e) = entry {
-                        if let Ok(fsname) = get_file_path(&e.path()) {
+                        for fsname in get_file_paths(&e.path()).into_iter().flatten() {
                             let fsname = target.join(&fsname);
 
                             if !fsname.exists() {
@@ -661,6 +912,7 @@ async fn run() -> anyhow::Result<()> {
         Command::Pdbstore {
             filepath,
             targetpath,
+            embedded,
         } => {
             /* List all files in the directory specified by args[2] */
             let listing = recursive_listdir(&filepath);
@@ -668,7 +920,7 @@ async fn run() -> anyhow::Result<()> {
             listing
                 .for_each(|entry| async {
                     if let Ok(e) = entry {
-                        if let Ok(fsname) = get_pdb_path(&e.path()) {
+                        if let Ok(fsname) = get_pdb_path(e.path()) {
                             let fsname = targetpath.join(&fsname);
 
                             if !fsname.exists() {
@@ -681,15 +933,51 @@ async fn run() -> anyhow::Result<()> {
                                     println!("Failed to copy file {:?}: {err:#}", &e.path());
                                 }
                             }
+                        } else if embedded {
+                            if let Ok(Some((fsname, pdb))) = get_embedded_pdb(&e.path()) {
+                                let fsname = targetpath.join(&fsname);
+
+                                if !fsname.exists() {
+                                    let dir = fsname.parent().unwrap();
+                                    tokio::fs::create_dir_all(dir)
+                                        .await
+                                        .expect("Failed to create filestore directory");
+
+                                    if let Err(err) = tokio::fs::write(&fsname, pdb).await {
+                                        println!(
+                                            "Failed to extract embedded PDB {:?}: {err:#}",
+                                            &e.path()
+                                        );
+                                    }
+                                }
+                            }
                         }
                     }
                 })
                 .await;
         }
+        Command::Cache(c) => match c {
+            CacheCommand::Verify { cache_path, remove } => {
+                if verify_cache(&cache_path, remove).await? != 0 {
+                    std::process::exit(1);
+                }
+            }
+        },
         Command::Info(i) => match i {
-            InfoCommand::Pdbhash { filepath } => {
-                let pdb = get_pdb(&filepath)?;
-                println!("{}", pdb);
+            InfoCommand::Pdbhash { filepath, kind } => {
+                let info = pe::PeDebugInfo::parse(&filepath)?;
+                if info.codeview.is_empty() {
+                    anyhow::bail!("Failed to find a codeview directory");
+                }
+
+                for cv in &info.codeview {
+                    let e = ManifestEntry::new(cv.pdb_name(), &cv.sym_info());
+                    if kind {
+                        println!("{}\t{}", e, cv.kind());
+                    } else {
+                        println!("{}", e);
+                    }
+                }
             }
         },
     }
@@ -697,7 +985,38 @@ async fn run() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Wait for the user to ask us to stop, with Ctrl-C or (on Unix) SIGTERM.
+async fn shutdown_requested() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+            return;
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 #[tokio::main]
 async fn main() {
-    run().await.unwrap();
+    // N.B: Stopping by dropping `run` cancels the downloads in progress, which
+    // removes their temporary files and keeps what they got for the next run to resume.
+    let interrupted = tokio::select! {
+        res = run() => {
+            res.unwrap();
+            false
+        }
+        _ = shutdown_requested() => true,
+    };
+
+    if interrupted {
+        eprintln!("Interrupted");
+        std::process::exit(130);
+    }
 }
@@ -1,9 +1,13 @@
 //! Contains functionality for parsing MZ/PE files
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
 use std::path::Path;
 
+use anyhow::Context;
+use filebuffer::FileBuffer;
 use zerocopy::{AsBytes, FromBytes};
 
+use crate::symsrv::{checksum::PdbChecksum, ExeInfo, Nb10Info, PdbInfo, SymFileInfo};
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, AsBytes, FromBytes)]
 pub struct MZHeader {
@@ -37,9 +41,19 @@ pub struct PEHeader {
     pub characteristics: u16,
 }
 
-const IMAGE_FILE_MACHINE_I386: u16 = 0x014c;
-const IMAGE_FILE_MACHINE_IA64: u16 = 0x0200;
-const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+pub const IMAGE_FILE_MACHINE_I386: u16 = 0x014c;
+pub const IMAGE_FILE_MACHINE_ARM: u16 = 0x01c0;
+pub const IMAGE_FILE_MACHINE_ARMNT: u16 = 0x01c4;
+pub const IMAGE_FILE_MACHINE_IA64: u16 = 0x0200;
+pub const IMAGE_FILE_MACHINE_ARM64EC: u16 = 0xa641;
+pub const IMAGE_FILE_MACHINE_ARM64X: u16 = 0xa64e;
+pub const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+pub const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+
+/// Optional header magic for PE32 images
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x10b;
+/// Optional header magic for PE32+ images
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
 
 #[repr(C, packed)]
 #[derive(Clone, Copy, AsBytes, FromBytes)]
@@ -156,22 +170,42 @@ pub struct CodeviewEntry {
     pub age: u32,
 }
 
+/// Legacy (VC6 era) CodeView record.
+#[repr(C, packed)]
+#[derive(Clone, Copy, AsBytes, FromBytes)]
+pub struct CodeviewNb10Entry {
+    pub signature: [u8; 4], // NB10
+    pub offset: u32,
+    pub timestamp: u32,
+    pub age: u32,
+}
+
 pub const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+pub const IMAGE_DEBUG_TYPE_EMBEDDED_PORTABLE_PDB: u32 = 17;
+pub const IMAGE_DEBUG_TYPE_PDBCHECKSUM: u32 = 19;
+
+/// The minor version of a CodeView debug directory entry that refers to a
+/// portable PDB ("PM").
+pub const PORTABLE_PDB_MINOR_VERSION: u16 = 0x504d;
 
-/// Read a structure from a file stream, directly interpreting the raw bytes
-/// of the file as T.
-pub fn read_struct<T: AsBytes + FromBytes>(fd: &mut std::fs::File) -> io::Result<T> {
+pub const IMAGE_DIRECTORY_ENTRY_DEBUG: usize = 6;
+pub const IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR: usize = 14;
+
+/// Read a structure from a stream, directly interpreting the raw bytes
+/// of the stream as T.
+pub fn read_struct<T: AsBytes + FromBytes, R: Read>(fd: &mut R) -> io::Result<T> {
     let mut ret: T = T::new_zeroed();
     fd.read_exact(ret.as_bytes_mut())?;
 
     Ok(ret)
 }
 
-pub fn parse_pe(filename: &Path) -> anyhow::Result<(std::fs::File, MZHeader, PEHeader, u32, u32)> {
-    let mut fd = std::fs::File::open(filename)?;
-
+/// Parse the MZ, PE and optional headers of an image.
+///
+/// On success, `fd` is left positioned at the start of the data directories.
+pub fn parse_pe<R: Read + Seek>(fd: &mut R) -> anyhow::Result<(MZHeader, PEHeader, u32, u32)> {
     /* Check for an MZ header */
-    let mz_header: MZHeader = read_struct(&mut fd)?;
+    let mz_header: MZHeader = read_struct(fd)?;
     if &mz_header.signature != b"MZ" {
         anyhow::bail!("No MZ header present");
     }
@@ -182,23 +216,1009 @@ pub fn parse_pe(filename: &Path) -> anyhow::Result<(std::fs::File, MZHeader, PEH
     }
 
     /* Check for a PE header */
-    let pe_header: PEHeader = read_struct(&mut fd)?;
+    let pe_header: PEHeader = read_struct(fd)?;
     if &pe_header.signature != b"PE\0\0" {
         anyhow::bail!("No PE header present");
     }
 
+    match pe_header.machine {
+        IMAGE_FILE_MACHINE_I386
+        | IMAGE_FILE_MACHINE_ARM
+        | IMAGE_FILE_MACHINE_ARMNT
+        | IMAGE_FILE_MACHINE_IA64
+        | IMAGE_FILE_MACHINE_ARM64EC
+        | IMAGE_FILE_MACHINE_ARM64X
+        | IMAGE_FILE_MACHINE_AMD64
+        | IMAGE_FILE_MACHINE_ARM64 => {}
+        _ => anyhow::bail!("Unsupported PE machine type"),
+    }
+
+    /* Peek at the optional header magic to determine its layout. The machine
+     * type does not reliably tell us this (e.g. ARM64EC and ARM64X images).
+     */
+    let mut magic = [0u8; 2];
+    fd.read_exact(&mut magic)?;
+    fd.seek(SeekFrom::Current(-2))?;
+
     /* Grab the number of tables from the bitness-specific table */
-    let (image_size, num_tables) = match pe_header.machine {
-        IMAGE_FILE_MACHINE_I386 => {
-            let opthdr: WindowsPEHeader32 = read_struct(&mut fd)?;
+    let (image_size, num_tables) = match u16::from_le_bytes(magic) {
+        IMAGE_NT_OPTIONAL_HDR32_MAGIC => {
+            let opthdr: WindowsPEHeader32 = read_struct(fd)?;
             (opthdr.size_of_image, opthdr.num_tables)
         }
-        IMAGE_FILE_MACHINE_IA64 | IMAGE_FILE_MACHINE_AMD64 => {
-            let opthdr: WindowsPEHeader64 = read_struct(&mut fd)?;
+        IMAGE_NT_OPTIONAL_HDR64_MAGIC => {
+            let opthdr: WindowsPEHeader64 = read_struct(fd)?;
             (opthdr.size_of_image, opthdr.num_tables)
         }
-        _ => anyhow::bail!("Unsupported PE machine type"),
+        _ => anyhow::bail!("Unsupported PE optional header magic"),
     };
 
-    Ok((fd, mz_header, pe_header, image_size, num_tables))
+    Ok((mz_header, pe_header, image_size, num_tables))
+}
+
+/// The signature identifying a PDB in a CodeView record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PdbSignature {
+    /// A GUID, from an `RSDS` record.
+    Guid(u128),
+    /// A timestamp, from a legacy `NB10` record.
+    Timestamp(u32),
+}
+
+/// The kind of a CodeView record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CodeviewKind {
+    /// A native PDB referenced by an `RSDS` record.
+    Rsds,
+    /// A native PDB referenced by a legacy `NB10` record.
+    Nb10,
+    /// A .NET portable PDB.
+    Portable,
+}
+
+impl std::fmt::Display for CodeviewKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CodeviewKind::Rsds => "rsds",
+            CodeviewKind::Nb10 => "nb10",
+            CodeviewKind::Portable => "portable",
+        })
+    }
+}
+
+/// A CodeView record pointing at the PDB for an image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodeviewInfo {
+    pub signature: PdbSignature,
+    pub age: u32,
+    /// The PDB path as recorded by the linker. This may be a full path.
+    pub path: String,
+    /// Whether the record refers to a .NET portable PDB rather than a native one.
+    pub portable: bool,
+}
+
+impl CodeviewInfo {
+    /// The filename component of the recorded PDB path.
+    ///
+    /// The path was recorded on the build machine, so both `/` and `\` are
+    /// treated as separators regardless of the host platform.
+    pub fn pdb_name(&self) -> &str {
+        self.path.rsplit(['/', '\\']).next().unwrap_or(&self.path)
+    }
+
+    /// The kind of this record.
+    pub fn kind(&self) -> CodeviewKind {
+        if self.portable {
+            return CodeviewKind::Portable;
+        }
+
+        match self.signature {
+            PdbSignature::Guid(_) => CodeviewKind::Rsds,
+            PdbSignature::Timestamp(_) => CodeviewKind::Nb10,
+        }
+    }
+
+    /// The symbol server key for the PDB.
+    pub fn sym_info(&self) -> SymFileInfo {
+        match self.signature {
+            PdbSignature::Guid(guid) if self.portable => SymFileInfo::PortablePdb(PdbInfo {
+                guid,
+                age: self.age,
+            }),
+            PdbSignature::Guid(guid) => SymFileInfo::Pdb(PdbInfo {
+                guid,
+                age: self.age,
+            }),
+            PdbSignature::Timestamp(timestamp) => SymFileInfo::Nb10(Nb10Info {
+                timestamp,
+                age: self.age,
+            }),
+        }
+    }
+}
+
+/// The location of a debug directory entry's raw data within the image file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DebugData {
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl DebugData {
+    fn new(de: &ImageDebugDirectory) -> Self {
+        Self {
+            offset: de.pointer_to_raw_data,
+            size: de.size_of_data,
+        }
+    }
+
+    /// Read the raw data from the image it was found in.
+    pub fn read<R: Read + Seek>(&self, fd: &mut R) -> anyhow::Result<Vec<u8>> {
+        let offset = self.offset as u64;
+
+        // N.B: Check the size against the image before trusting it with an allocation.
+        let len = fd.seek(SeekFrom::End(0))?;
+        if offset.saturating_add(self.size as u64) > len {
+            anyhow::bail!("Debug data extends past the end of the image");
+        }
+
+        if fd.seek(SeekFrom::Start(offset))? != offset {
+            anyhow::bail!("Failed to seek to debug data");
+        }
+
+        let mut data = vec![0u8; self.size as usize];
+        fd.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+/// Debug information relevant to a symbol server extracted from a PE image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeDebugInfo {
+    pub machine: u16,
+    pub timestamp: u32,
+    pub size_of_image: u32,
+    /// Whether the image has a CLR runtime header, i.e. is a .NET assembly.
+    pub managed: bool,
+    /// Every distinct CodeView record found in the debug directory, in
+    /// directory order.
+    pub codeview: Vec<CodeviewInfo>,
+    /// The compressed portable PDB embedded in the image, if any.
+    pub embedded_pdb: Option<DebugData>,
+    /// The expected checksums of the image's PDB.
+    pub pdb_checksums: Vec<PdbChecksum>,
+}
+
+impl PeDebugInfo {
+    /// Parse the headers and debug directory of the PE file at `filename`.
+    ///
+    /// The file is memory-mapped rather than read piecemeal, which avoids
+    /// issuing a syscall for every header we look at.
+    pub fn parse(filename: &Path) -> anyhow::Result<Self> {
+        let buf = FileBuffer::open(filename)?;
+        Self::from_bytes(&buf)
+    }
+
+    /// Parse the headers and debug directory of a PE image held in memory.
+    pub fn from_bytes(buf: &[u8]) -> anyhow::Result<Self> {
+        Self::from_reader(&mut Cursor::new(buf))
+    }
+
+    /// Parse the headers and debug directory of a PE image from an arbitrary
+    /// seekable stream, e.g. a file inside of an archive.
+    ///
+    /// An image without a debug directory is not an error; it simply has no
+    /// CodeView records.
+    pub fn from_reader<R: Read + Seek>(fd: &mut R) -> anyhow::Result<Self> {
+        let (mz_header, pe_header, size_of_image, num_tables) = parse_pe(fd)?;
+        let data_dirs = read_data_directories(fd, num_tables)?;
+        let entries = read_debug_directory(fd, &mz_header, &pe_header, &data_dirs)?;
+
+        /* Managed assemblies have a CLR runtime header */
+        let managed = matches!(
+            data_dirs.get(IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR),
+            Some(d) if d.vaddr != 0 && d.size != 0
+        );
+
+        /* Look through all debug table entries for codeview entries. A single
+         * malformed or unrecognized record should not hide the others, and
+         * neither should a malformed checksum entry.
+         */
+        let mut codeview: Vec<CodeviewInfo> = Vec::new();
+        for de in entries
+            .iter()
+            .filter(|de| de.typ == IMAGE_DEBUG_TYPE_CODEVIEW)
+        {
+            if let Ok(cv) = read_codeview_entry(fd, de, managed) {
+                if !codeview.contains(&cv) {
+                    codeview.push(cv);
+                }
+            }
+        }
+
+        let embedded_pdb = entries
+            .iter()
+            .find(|de| de.typ == IMAGE_DEBUG_TYPE_EMBEDDED_PORTABLE_PDB)
+            .map(DebugData::new);
+
+        let mut pdb_checksums = Vec::new();
+        for de in entries
+            .iter()
+            .filter(|de| de.typ == IMAGE_DEBUG_TYPE_PDBCHECKSUM)
+        {
+            let checksum = DebugData::new(de)
+                .read(fd)
+                .and_then(|data| PdbChecksum::parse(&data));
+            if let Ok(c) = checksum {
+                pdb_checksums.push(c);
+            }
+        }
+
+        Ok(Self {
+            machine: pe_header.machine,
+            timestamp: pe_header.timestamp,
+            size_of_image,
+            managed,
+            codeview,
+            embedded_pdb,
+            pdb_checksums,
+        })
+    }
+
+    /// The symbol server key for the image itself.
+    pub fn exe_info(&self) -> SymFileInfo {
+        SymFileInfo::Exe(ExeInfo {
+            timestamp: self.timestamp,
+            size: self.size_of_image,
+        })
+    }
+
+    /// The CodeView record a debugger would use for this image.
+    ///
+    /// This is the first record of the most preferred kind, with native `RSDS`
+    /// records preferred over legacy `NB10` ones, and native PDBs preferred
+    /// over portable ones.
+    pub fn primary(&self) -> Option<&CodeviewInfo> {
+        self.codeview.iter().min_by_key(|cv| cv.kind())
+    }
+
+    /// Decompress the portable PDB embedded in the image, returning it along
+    /// with the CodeView record identifying it.
+    ///
+    /// `fd` must be the image this information was parsed from.
+    pub fn read_embedded_pdb<R: Read + Seek>(
+        &self,
+        fd: &mut R,
+    ) -> anyhow::Result<Option<(&CodeviewInfo, Vec<u8>)>> {
+        let embedded = match self.embedded_pdb {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+
+        /* The embedded PDB's ID matches the portable PDB codeview record */
+        let cv = self
+            .codeview
+            .iter()
+            .find(|cv| cv.kind() == CodeviewKind::Portable)
+            .context("No portable PDB codeview record for embedded PDB")?;
+
+        let data = embedded.read(fd)?;
+        Ok(Some((cv, decompress_embedded_pdb(&data)?)))
+    }
+
+    /// The checksums the PDB referenced by `cv` is expected to match.
+    ///
+    /// In a managed image the checksums describe the portable PDB, so they do
+    /// not apply to any native PDB the image also references.
+    pub fn checksums_for(&self, cv: &CodeviewInfo) -> &[PdbChecksum] {
+        if !self.managed || cv.portable {
+            &self.pdb_checksums
+        } else {
+            &[]
+        }
+    }
+
+    /// The PDB name and symbol server key for every CodeView record.
+    pub fn pdbs(&self) -> impl Iterator<Item = (&str, SymFileInfo)> {
+        self.codeview
+            .iter()
+            .map(|cv| (cv.pdb_name(), cv.sym_info()))
+    }
+}
+
+/// Extract the portable PDB embedded in the PE file at `filename`, along with
+/// the CodeView record identifying it.
+pub fn extract_embedded_pdb(filename: &Path) -> anyhow::Result<Option<(CodeviewInfo, Vec<u8>)>> {
+    let buf = FileBuffer::open(filename)?;
+    let info = PeDebugInfo::from_bytes(&buf)?;
+
+    Ok(info
+        .read_embedded_pdb(&mut Cursor::new(&*buf))?
+        .map(|(cv, pdb)| (cv.clone(), pdb)))
+}
+
+/// Decompress the raw data of an `IMAGE_DEBUG_TYPE_EMBEDDED_PORTABLE_PDB`
+/// entry: an "MPDB" signature and the uncompressed size, followed by the
+/// deflate-compressed PDB.
+fn decompress_embedded_pdb(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if data.len() < 8 || &data[..4] != b"MPDB" {
+        anyhow::bail!("No MPDB signature present in embedded PDB");
+    }
+
+    let size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as u64;
+
+    /* Don't trust the recorded size any further than one byte past it */
+    let mut pdb = Vec::new();
+    flate2::read::DeflateDecoder::new(&data[8..])
+        .take(size + 1)
+        .read_to_end(&mut pdb)
+        .context("Failed to decompress embedded PDB")?;
+    if pdb.len() as u64 != size {
+        anyhow::bail!("Embedded PDB size does not match its header");
+    }
+
+    Ok(pdb)
+}
+
+/// Read the data directories of an image. `fd` must be positioned at the
+/// start of the data directories.
+fn read_data_directories<R: Read>(
+    fd: &mut R,
+    num_tables: u32,
+) -> anyhow::Result<Vec<ImageDataDirectory>> {
+    /* Load all the data directories into a vector */
+    let mut data_dirs = Vec::new();
+    for _ in 0..num_tables {
+        let datadir: ImageDataDirectory = read_struct(fd)?;
+        data_dirs.push(datadir);
+    }
+
+    Ok(data_dirs)
+}
+
+/// Read all entries of the debug directory of an image.
+fn read_debug_directory<R: Read + Seek>(
+    fd: &mut R,
+    mz_header: &MZHeader,
+    pe_header: &PEHeader,
+    data_dirs: &[ImageDataDirectory],
+) -> anyhow::Result<Vec<ImageDebugDirectory>> {
+    /* Debug directory is at offset 6, no debug directory means no records */
+    let debug_table = match data_dirs.get(IMAGE_DIRECTORY_ENTRY_DEBUG) {
+        Some(d) if d.vaddr != 0 => *d,
+        _ => return Ok(Vec::new()),
+    };
+
+    /* Validate debug table size is sane, which also rejects an empty table */
+    let iddlen = std::mem::size_of::<ImageDebugDirectory>() as u32;
+    let debug_table_ents = debug_table.size / iddlen;
+    if (debug_table.size % iddlen) != 0 || debug_table_ents == 0 {
+        anyhow::bail!("No debug entries or not mod ImageDebugDirectory");
+    }
+
+    /* Seek to where the section table should be */
+    let section_headers =
+        mz_header.new_header as u64 + 0x18 + pe_header.optional_header_size as u64;
+    if fd.seek(SeekFrom::Start(section_headers))? != section_headers {
+        anyhow::bail!("Failed to seek to section table");
+    }
+
+    /* Parse all the sections into a vector */
+    let mut sections = Vec::new();
+    for _ in 0..pe_header.num_sections {
+        let sechdr: ImageSectionHeader = read_struct(fd)?;
+        sections.push(sechdr);
+    }
+
+    let debug_table_last = debug_table
+        .size
+        .checked_sub(1)
+        .and_then(|n| debug_table.vaddr.checked_add(n))
+        .context("Debug table runs past the end of the address space")?;
+
+    let debug_raw_ptr = {
+        /* Find the section the debug table belongs to */
+        let mut debug_data = None;
+        for section in &sections {
+            /* We use raw_data_size instead of vsize as we are not loading the
+             * file and only care about raw contents in the file.
+             */
+            let secrange = match section.vaddr.checked_add(section.raw_data_size) {
+                Some(end) => section.vaddr..end,
+                None => continue,
+            };
+
+            /* Check if the entire debug table is contained in this sections
+             * virtual address range.
+             */
+            if secrange.contains(&{ debug_table.vaddr }) && secrange.contains(&debug_table_last) {
+                debug_data = debug_table
+                    .vaddr
+                    .checked_sub(section.vaddr)
+                    .and_then(|n| n.checked_add(section.pointer_to_raw_data));
+                break;
+            }
+        }
+
+        match debug_data {
+            Some(d) => d as u64,
+            None => anyhow::bail!("Unable to find debug data"),
+        }
+    };
+
+    /* Seek to where the debug directories should be */
+    if fd.seek(SeekFrom::Start(debug_raw_ptr))? != debug_raw_ptr {
+        anyhow::bail!("Failed to seek to debug directories");
+    }
+
+    let mut entries = Vec::new();
+    for _ in 0..debug_table_ents {
+        let de: ImageDebugDirectory = read_struct(fd)?;
+        entries.push(de);
+    }
+
+    Ok(entries)
+}
+
+/// Read the CodeView record referenced by the debug directory entry `de`.
+///
+/// `managed` indicates whether the image is a .NET assembly, which is the
+/// only place a record can refer to a portable PDB.
+fn read_codeview_entry<R: Read + Seek>(
+    fd: &mut R,
+    de: &ImageDebugDirectory,
+    managed: bool,
+) -> anyhow::Result<CodeviewInfo> {
+    /* Seek to where the codeview entry should be */
+    let cvo = de.pointer_to_raw_data as u64;
+
+    // N.B: Check the size against the image before trusting it with an allocation.
+    let len = fd.seek(SeekFrom::End(0))?;
+    if cvo.saturating_add(de.size_of_data as u64) > len {
+        anyhow::bail!("Codeview entry extends past the end of the image");
+    }
+
+    if fd.seek(SeekFrom::Start(cvo))? != cvo {
+        anyhow::bail!("Failed to seek to codeview entry");
+    }
+
+    /* Peek at the signature to determine the record layout */
+    let mut sig = [0u8; 4];
+    fd.read_exact(&mut sig)?;
+    fd.seek(SeekFrom::Current(-4))?;
+
+    let (signature, age, header_len) = match &sig {
+        b"RSDS" => {
+            let cv: CodeviewEntry = read_struct(fd)?;
+            let guid = (cv.guid_a as u128) << 96
+                | (cv.guid_b as u128) << 80
+                | (cv.guid_c as u128) << 64
+                | u64::from_be_bytes(cv.guid_d) as u128;
+
+            (PdbSignature::Guid(guid), cv.age, std::mem::size_of_val(&cv))
+        }
+        b"NB10" => {
+            let cv: CodeviewNb10Entry = read_struct(fd)?;
+            (
+                PdbSignature::Timestamp(cv.timestamp),
+                cv.age,
+                std::mem::size_of_val(&cv),
+            )
+        }
+        _ => anyhow::bail!("No RSDS or NB10 signature present in codeview ent"),
+    };
+
+    /* Calculate theoretical string length based on the size of the
+     * section vs the size of the header */
+    let cv_strlen = (de.size_of_data as usize)
+        .checked_sub(header_len)
+        .context("Codeview entry too small")?;
+
+    /* Read in the debug path */
+    let mut dpath = vec![0u8; cv_strlen];
+    fd.read_exact(&mut dpath)?;
+
+    /* PDB strings are utf8 and null terminated, find the first null
+     * and we will split it there.
+     */
+    let null_strlen = dpath
+        .iter()
+        .position(|&x| x == 0)
+        .context("Failed to find null terminiator in codeview ent")?;
+    let path = std::str::from_utf8(&dpath[..null_strlen])?;
+    if path.is_empty() {
+        anyhow::bail!("Could not parse file from codeview path");
+    }
+
+    Ok(CodeviewInfo {
+        signature,
+        age,
+        path: path.to_string(),
+        portable: managed
+            && de.minor_version == PORTABLE_PDB_MINOR_VERSION
+            && matches!(signature, PdbSignature::Guid(_)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A debug directory entry for a synthetic test image.
+    struct DebugEntry {
+        typ: u32,
+        major_version: u16,
+        minor_version: u16,
+        data: Vec<u8>,
+    }
+
+    /// Build the raw contents of an RSDS CodeView record.
+    fn rsds(guid: u128, age: u32, path: &str) -> DebugEntry {
+        let mut data = b"RSDS".to_vec();
+        data.extend_from_slice(&((guid >> 96) as u32).to_le_bytes());
+        data.extend_from_slice(&((guid >> 80) as u16).to_le_bytes());
+        data.extend_from_slice(&((guid >> 64) as u16).to_le_bytes());
+        data.extend_from_slice(&(guid as u64).to_be_bytes());
+        data.extend_from_slice(&age.to_le_bytes());
+        data.extend_from_slice(path.as_bytes());
+        data.push(0);
+
+        DebugEntry {
+            typ: IMAGE_DEBUG_TYPE_CODEVIEW,
+            major_version: 0,
+            minor_version: 0,
+            data,
+        }
+    }
+
+    /// Build the raw contents of an RSDS CodeView record for a portable PDB.
+    fn portable(guid: u128, path: &str) -> DebugEntry {
+        DebugEntry {
+            major_version: 0x0100,
+            minor_version: PORTABLE_PDB_MINOR_VERSION,
+            ..rsds(guid, 1, path)
+        }
+    }
+
+    /// Build the raw contents of an NB10 CodeView record.
+    fn nb10(timestamp: u32, age: u32, path: &str) -> DebugEntry {
+        let mut data = b"NB10".to_vec();
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data.extend_from_slice(&age.to_le_bytes());
+        data.extend_from_slice(path.as_bytes());
+        data.push(0);
+
+        DebugEntry {
+            typ: IMAGE_DEBUG_TYPE_CODEVIEW,
+            major_version: 0,
+            minor_version: 0,
+            data,
+        }
+    }
+
+    /// Build a minimal PE image with a single section holding the debug
+    /// directory and the raw data of every entry.
+    fn build_image(machine: u16, pe32plus: bool, entries: &[DebugEntry]) -> Vec<u8> {
+        build_image_ex(machine, pe32plus, false, entries)
+    }
+
+    /// Build a minimal PE image, optionally with a CLR runtime header.
+    fn build_image_ex(
+        machine: u16,
+        pe32plus: bool,
+        managed: bool,
+        entries: &[DebugEntry],
+    ) -> Vec<u8> {
+        const NUM_TABLES: usize = 16;
+        const SECTION_VADDR: u32 = 0x1000;
+        const SECTION_OFFSET: u32 = 0x200;
+
+        let opt_size = if pe32plus {
+            std::mem::size_of::<WindowsPEHeader64>()
+        } else {
+            std::mem::size_of::<WindowsPEHeader32>()
+        };
+
+        // Lay out the section: debug directory first, then each entry's data.
+        let iddlen = std::mem::size_of::<ImageDebugDirectory>();
+        let mut dirs = Vec::new();
+        let mut raw = Vec::new();
+        let mut data_offset = iddlen * entries.len();
+        for e in entries {
+            let mut de = ImageDebugDirectory::new_zeroed();
+            de.major_version = e.major_version;
+            de.minor_version = e.minor_version;
+            de.typ = e.typ;
+            de.size_of_data = e.data.len() as u32;
+            de.address_of_raw_data = SECTION_VADDR + data_offset as u32;
+            de.pointer_to_raw_data = SECTION_OFFSET + data_offset as u32;
+            dirs.extend_from_slice(de.as_bytes());
+            raw.extend_from_slice(&e.data);
+            data_offset += e.data.len();
+        }
+        let section_data = [dirs, raw].concat();
+
+        let mut mz = MZHeader::new_zeroed();
+        mz.signature = *b"MZ";
+        mz.new_header = std::mem::size_of::<MZHeader>() as u32;
+
+        let mut pe = PEHeader::new_zeroed();
+        pe.signature = *b"PE\0\0";
+        pe.machine = machine;
+        pe.num_sections = 1;
+        pe.timestamp = 0x5E8A1D2C;
+        pe.optional_header_size =
+            (opt_size + NUM_TABLES * std::mem::size_of::<ImageDataDirectory>()) as u16;
+
+        let mut image = Vec::new();
+        image.extend_from_slice(mz.as_bytes());
+        image.extend_from_slice(pe.as_bytes());
+        if pe32plus {
+            let mut opt = WindowsPEHeader64::new_zeroed();
+            opt.magic = IMAGE_NT_OPTIONAL_HDR64_MAGIC;
+            opt.size_of_image = 0x3000;
+            opt.num_tables = NUM_TABLES as u32;
+            image.extend_from_slice(opt.as_bytes());
+        } else {
+            let mut opt = WindowsPEHeader32::new_zeroed();
+            opt.magic = IMAGE_NT_OPTIONAL_HDR32_MAGIC;
+            opt.size_of_image = 0x3000;
+            opt.num_tables = NUM_TABLES as u32;
+            image.extend_from_slice(opt.as_bytes());
+        }
+
+        for i in 0..NUM_TABLES {
+            let mut dd = ImageDataDirectory::new_zeroed();
+            if i == IMAGE_DIRECTORY_ENTRY_DEBUG && !entries.is_empty() {
+                dd.vaddr = SECTION_VADDR;
+                dd.size = (iddlen * entries.len()) as u32;
+            }
+            if i == IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR && managed {
+                // Only the presence of the header matters to the parser.
+                dd.vaddr = SECTION_VADDR;
+                dd.size = 0x48;
+            }
+            image.extend_from_slice(dd.as_bytes());
+        }
+
+        let mut sec = ImageSectionHeader::new_zeroed();
+        sec.name = *b".rdata\0\0";
+        sec.vsize = section_data.len() as u32;
+        sec.vaddr = SECTION_VADDR;
+        sec.raw_data_size = section_data.len() as u32;
+        sec.pointer_to_raw_data = SECTION_OFFSET;
+        image.extend_from_slice(sec.as_bytes());
+
+        image.resize(SECTION_OFFSET as usize, 0);
+        image.extend_from_slice(&section_data);
+        image
+    }
+
+    #[test]
+    fn parse_machines() {
+        let guid = 0x32C1A669_D5FF_EFD4_1091_F636CFDB6E99;
+
+        for (machine, pe32plus) in [
+            (IMAGE_FILE_MACHINE_I386, false),
+            (IMAGE_FILE_MACHINE_ARMNT, false),
+            (IMAGE_FILE_MACHINE_AMD64, true),
+            (IMAGE_FILE_MACHINE_ARM64, true),
+            (IMAGE_FILE_MACHINE_ARM64EC, true),
+        ] {
+            let image = build_image(machine, pe32plus, &[rsds(guid, 2, "C:\\out\\foo.pdb")]);
+            let info = PeDebugInfo::from_bytes(&image).unwrap();
+
+            assert_eq!(info.machine, machine);
+            assert_eq!(info.exe_info().to_string(), "5e8a1d2c3000");
+            assert_eq!(
+                info.pdbs().collect::<Vec<_>>(),
+                vec![("foo.pdb", SymFileInfo::Pdb(PdbInfo { guid, age: 2 }))]
+            );
+        }
+    }
+
+    #[test]
+    fn parse_nb10() {
+        let image = build_image(
+            IMAGE_FILE_MACHINE_I386,
+            false,
+            &[nb10(0x3A1B2C3D, 5, "d:\\drv\\obj\\i386\\olddrv.pdb")],
+        );
+        let info = PeDebugInfo::from_bytes(&image).unwrap();
+
+        assert_eq!(
+            info.codeview,
+            vec![CodeviewInfo {
+                signature: PdbSignature::Timestamp(0x3A1B2C3D),
+                age: 5,
+                path: "d:\\drv\\obj\\i386\\olddrv.pdb".to_string(),
+                portable: false,
+            }]
+        );
+        assert_eq!(
+            info.pdbs()
+                .map(|(name, info)| format!("{name},{info}"))
+                .collect::<Vec<_>>(),
+            vec!["olddrv.pdb,3A1B2C3D5"]
+        );
+    }
+
+    #[test]
+    fn parse_multiple_codeview() {
+        let guid_a = 0x11111111_2222_3333_4444_555555555555;
+        let guid_b = 0xAAAAAAAA_BBBB_CCCC_DDDD_EEEEEEEEEEEE;
+        let mut bogus = rsds(0, 0, "bogus.pdb");
+        bogus.data[..4].copy_from_slice(b"NB09");
+
+        let image = build_image(
+            IMAGE_FILE_MACHINE_ARM64,
+            true,
+            &[
+                nb10(0x3A1B2C3D, 1, "legacy.pdb"),
+                bogus,
+                rsds(guid_a, 1, "hybrid.pdb"),
+                rsds(guid_a, 1, "hybrid.pdb"),
+                rsds(guid_b, 3, "hybrid_ec.pdb"),
+            ],
+        );
+        let info = PeDebugInfo::from_bytes(&image).unwrap();
+
+        assert_eq!(
+            info.codeview
+                .iter()
+                .map(|cv| (cv.kind(), cv.pdb_name()))
+                .collect::<Vec<_>>(),
+            vec![
+                (CodeviewKind::Nb10, "legacy.pdb"),
+                (CodeviewKind::Rsds, "hybrid.pdb"),
+                (CodeviewKind::Rsds, "hybrid_ec.pdb"),
+            ]
+        );
+        assert_eq!(info.primary().unwrap().pdb_name(), "hybrid.pdb");
+    }
+
+    #[test]
+    fn parse_portable() {
+        let guid = 0x8E2B2A10_2C61_4C3A_9E3F_0AF2F3A4B5C6;
+        let native = 0x01234567_89AB_CDEF_0123_456789ABCDEF;
+        let entries = [
+            portable(guid, "/src/obj/Release/Contoso.Widgets.pdb"),
+            rsds(native, 2, "Contoso.Widgets.ni.pdb"),
+        ];
+
+        let image = build_image_ex(IMAGE_FILE_MACHINE_I386, false, true, &entries);
+        let info = PeDebugInfo::from_bytes(&image).unwrap();
+
+        assert!(info.managed);
+        assert_eq!(
+            info.pdbs()
+                .map(|(name, info)| format!("{name},{info}"))
+                .collect::<Vec<_>>(),
+            vec![
+                "Contoso.Widgets.pdb,8E2B2A102C614C3A9E3F0AF2F3A4B5C6FFFFFFFF",
+                "Contoso.Widgets.ni.pdb,0123456789ABCDEF0123456789ABCDEF2",
+            ]
+        );
+        assert_eq!(info.codeview[0].kind(), CodeviewKind::Portable);
+        assert_eq!(info.primary().unwrap().kind(), CodeviewKind::Rsds);
+
+        // Without a CLR header, the version fields are not meaningful.
+        let image = build_image(IMAGE_FILE_MACHINE_I386, false, &entries);
+        let info = PeDebugInfo::from_bytes(&image).unwrap();
+
+        assert!(!info.managed);
+        assert_eq!(info.codeview[0].kind(), CodeviewKind::Rsds);
+    }
+
+    #[test]
+    fn embedded_portable_pdb() {
+        use flate2::{write::DeflateEncoder, Compression};
+        use std::io::Write;
+
+        let guid = 0x8E2B2A10_2C61_4C3A_9E3F_0AF2F3A4B5C6;
+        let pdb = b"BSJB\x01\x00\x01\x00 not really a portable pdb".repeat(8);
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&pdb).unwrap();
+        let mut data = b"MPDB".to_vec();
+        data.extend_from_slice(&(pdb.len() as u32).to_le_bytes());
+        data.extend_from_slice(&encoder.finish().unwrap());
+
+        let image = build_image_ex(
+            IMAGE_FILE_MACHINE_AMD64,
+            true,
+            true,
+            &[
+                portable(guid, "Contoso.Widgets.pdb"),
+                DebugEntry {
+                    typ: IMAGE_DEBUG_TYPE_EMBEDDED_PORTABLE_PDB,
+                    major_version: 0x0100,
+                    minor_version: 0x0100,
+                    data,
+                },
+            ],
+        );
+        let info = PeDebugInfo::from_bytes(&image).unwrap();
+        let (cv, extracted) = info
+            .read_embedded_pdb(&mut Cursor::new(&image))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(cv.pdb_name(), "Contoso.Widgets.pdb");
+        assert_eq!(
+            cv.sym_info().to_string(),
+            "8E2B2A102C614C3A9E3F0AF2F3A4B5C6FFFFFFFF"
+        );
+        assert_eq!(extracted, pdb);
+
+        assert!(decompress_embedded_pdb(b"MPDB\x10\x00\x00\x00").is_err());
+        assert!(decompress_embedded_pdb(b"NOPE\x00\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn parse_pdb_checksum() {
+        let guid = 0x8E2B2A10_2C61_4C3A_9E3F_0AF2F3A4B5C6;
+        let entries = [
+            portable(guid, "Contoso.Widgets.pdb"),
+            rsds(guid, 2, "Contoso.Widgets.ni.pdb"),
+            DebugEntry {
+                typ: IMAGE_DEBUG_TYPE_PDBCHECKSUM,
+                major_version: 1,
+                minor_version: 0,
+                data: b"SHA256\0\xde\xad\xbe\xef".to_vec(),
+            },
+        ];
+        let image = build_image_ex(IMAGE_FILE_MACHINE_AMD64, true, true, &entries);
+        let info = PeDebugInfo::from_bytes(&image).unwrap();
+
+        let expected = vec![PdbChecksum {
+            algorithm: "SHA256".to_string(),
+            checksum: vec![0xde, 0xad, 0xbe, 0xef],
+        }];
+        assert_eq!(info.pdb_checksums, expected);
+        assert_eq!(info.checksums_for(&info.codeview[0]), &expected[..]);
+        assert!(info.checksums_for(&info.codeview[1]).is_empty());
+    }
+
+    #[test]
+    fn parse_bad_pdb_checksum() {
+        let guid = 0x8E2B2A10_2C61_4C3A_9E3F_0AF2F3A4B5C6;
+        let checksum = |data: &[u8]| DebugEntry {
+            typ: IMAGE_DEBUG_TYPE_PDBCHECKSUM,
+            major_version: 1,
+            minor_version: 0,
+            data: data.to_vec(),
+        };
+        let entries = [
+            rsds(guid, 1, "Contoso.Widgets.pdb"),
+            checksum(b"SHA256\0\xde\xad"),
+            checksum(b"SHA256\0\xbe\xef"),
+        ];
+        let mut image = build_image(IMAGE_FILE_MACHINE_AMD64, true, &entries);
+
+        // Claim an enormous size for the first checksum entry.
+        let iddlen = std::mem::size_of::<ImageDebugDirectory>();
+        let size_of_data = 0x200 + iddlen + 16;
+        image[size_of_data..size_of_data + 4].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+
+        let info = PeDebugInfo::from_bytes(&image).unwrap();
+        assert_eq!(info.primary().unwrap().pdb_name(), "Contoso.Widgets.pdb");
+        assert_eq!(
+            info.pdb_checksums,
+            vec![PdbChecksum {
+                algorithm: "SHA256".to_string(),
+                checksum: vec![0xbe, 0xef],
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_bad_debug_table() {
+        let guid = 0x8E2B2A10_2C61_4C3A_9E3F_0AF2F3A4B5C6;
+        let image = build_image(
+            IMAGE_FILE_MACHINE_AMD64,
+            true,
+            &[rsds(guid, 1, "Contoso.Widgets.pdb")],
+        );
+        assert!(PeDebugInfo::from_bytes(&image).is_ok());
+
+        let data_dirs = std::mem::size_of::<MZHeader>()
+            + std::mem::size_of::<PEHeader>()
+            + std::mem::size_of::<WindowsPEHeader64>();
+        let debug_table = data_dirs + IMAGE_DIRECTORY_ENTRY_DEBUG * 8;
+        let section = data_dirs + 16 * 8;
+        let patched = |fields: &[(usize, u32)]| {
+            let mut image = image.clone();
+            for &(at, value) in fields {
+                image[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            PeDebugInfo::from_bytes(&image)
+        };
+
+        // An empty debug table.
+        assert!(patched(&[(debug_table + 4, 0)]).is_err());
+        // A debug table running past the end of the address space.
+        assert!(patched(&[(debug_table, 0xFFFF_FFF0)]).is_err());
+        // A section running past the end of the address space.
+        assert!(patched(&[(section + 16, 0xFFFF_FFF0)]).is_err());
+        // A debug table whose file offset doesn't fit in 32 bits.
+        assert!(patched(&[
+            (debug_table, 0x1010),
+            (section + 16, 0x10000),
+            (section + 20, 0xFFFF_FFF8),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn parse_bad_codeview_size() {
+        let guid = 0x8E2B2A10_2C61_4C3A_9E3F_0AF2F3A4B5C6;
+        let entries = [
+            rsds(guid, 1, "Contoso.Widgets.pdb"),
+            nb10(0x5E8A1D2C, 2, "Contoso.Gadgets.pdb"),
+        ];
+        let mut image = build_image(IMAGE_FILE_MACHINE_AMD64, true, &entries);
+
+        // Claim an enormous size for the first CodeView record.
+        let size_of_data = 0x200 + 16;
+        image[size_of_data..size_of_data + 4].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+
+        let info = PeDebugInfo::from_bytes(&image).unwrap();
+        assert_eq!(
+            info.codeview,
+            vec![CodeviewInfo {
+                signature: PdbSignature::Timestamp(0x5E8A1D2C),
+                age: 2,
+                path: "Contoso.Gadgets.pdb".to_string(),
+                portable: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_no_debug_directory() {
+        let image = build_image(IMAGE_FILE_MACHINE_AMD64, true, &[]);
+        let info = PeDebugInfo::from_reader(&mut Cursor::new(image)).unwrap();
+
+        assert!(info.codeview.is_empty());
+        assert!(info.primary().is_none());
+        assert!(info.embedded_pdb.is_none());
+    }
+
+    #[test]
+    fn parse_not_pe() {
+        assert!(PeDebugInfo::from_bytes(b"").is_err());
+        assert!(PeDebugInfo::from_bytes(b"\x7fELF\x02\x01\x01").is_err());
+    }
+
+    #[test]
+    fn pdb_name() {
+        let cv = |path: &str| CodeviewInfo {
+            signature: PdbSignature::Guid(0),
+            age: 1,
+            path: path.to_string(),
+            portable: false,
+        };
+
+        assert_eq!(cv("ntdll.pdb").pdb_name(), "ntdll.pdb");
+        assert_eq!(cv("C:\\build\\out\\ntdll.pdb").pdb_name(), "ntdll.pdb");
+        assert_eq!(cv("/home/build/out/libfoo.pdb").pdb_name(), "libfoo.pdb");
+    }
+
+    #[test]
+    fn pdb_sym_info() {
+        let cv = CodeviewInfo {
+            signature: PdbSignature::Guid(0x32C1A669_D5FF_EFD4_1091_F636CFDB6E99),
+            age: 1,
+            path: "ntkrnlmp.pdb".to_string(),
+            portable: false,
+        };
+
+        assert_eq!(
+            cv.sym_info().to_string(),
+            "32C1A669D5FFEFD41091F636CFDB6E991"
+        );
+    }
 }
@@ -0,0 +1,148 @@
+//! Expansion of files stored by `symstore /compress`, which places a CAB
+//! archive at `<name>/<hash>/<name with last char replaced by _>`.
+use std::io::{Read, Seek, Write};
+
+use anyhow::Context;
+
+/// The name a compressed copy of `name` is stored under, e.g. `ntdll.pd_`
+/// for `ntdll.pdb`.
+pub fn compressed_name(name: &str) -> String {
+    let mut compressed = name.to_string();
+    compressed.pop();
+    compressed.push('_');
+    compressed
+}
+
+/// Expand the single file held in the CAB archive `cabinet` into `out`,
+/// returning its size. The file is streamed a block at a time rather than
+/// held in memory.
+///
+/// Both MSZIP and LZX compressed cabinets are supported.
+pub fn expand<R: Read + Seek, W: Write>(cabinet: R, out: &mut W) -> anyhow::Result<u64> {
+    let mut cabinet = cab::Cabinet::new(cabinet).context("failed to parse cabinet")?;
+
+    let name = cabinet
+        .folder_entries()
+        .flat_map(|f| f.file_entries())
+        .map(|f| f.name().to_string())
+        .next()
+        .context("cabinet is empty")?;
+
+    let mut file = cabinet
+        .read_file(&name)
+        .context("failed to expand cabinet")?;
+    std::io::copy(&mut file, out).context("failed to expand cabinet")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// Expand `cabinet` in memory.
+    fn expand_to_vec(cabinet: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let mut file = Vec::new();
+        expand(Cursor::new(cabinet), &mut file)?;
+        Ok(file)
+    }
+
+    /// Build a cabinet holding `data` as `name` in a single LZX folder.
+    ///
+    /// `cab` can't write LZX, so this lays the cabinet out by hand, with `data`
+    /// (at most 32K) stored in one uncompressed LZX block.
+    fn lzx_cabinet(name: &str, data: &[u8]) -> Vec<u8> {
+        const HEADER_LEN: u32 = 36;
+        const FOLDER_LEN: u32 = 8;
+        let file_len = 16 + name.len() as u32 + 1;
+
+        // The LZX stream: no E8 translation, then a block header with type 3
+        // (uncompressed) and the 24-bit block size, padded to a 16-bit word and
+        // stored as little-endian words. The three repeated match offsets follow.
+        let bits = 3u32 << 28 | (data.len() as u32) << 4;
+        let mut lzx = Vec::new();
+        lzx.extend_from_slice(&((bits >> 16) as u16).to_le_bytes());
+        lzx.extend_from_slice(&(bits as u16).to_le_bytes());
+        for _ in 0..3 {
+            lzx.extend_from_slice(&1u32.to_le_bytes());
+        }
+        lzx.extend_from_slice(data);
+
+        let data_start = HEADER_LEN + FOLDER_LEN + file_len;
+        let total = data_start + 8 + lzx.len() as u32;
+
+        let mut cab = b"MSCF".to_vec();
+        cab.extend_from_slice(&0u32.to_le_bytes());
+        cab.extend_from_slice(&total.to_le_bytes());
+        cab.extend_from_slice(&0u32.to_le_bytes());
+        cab.extend_from_slice(&(HEADER_LEN + FOLDER_LEN).to_le_bytes());
+        cab.extend_from_slice(&0u32.to_le_bytes());
+        cab.extend_from_slice(&[3, 1]); // Version 1.3
+        cab.extend_from_slice(&1u16.to_le_bytes()); // Folders
+        cab.extend_from_slice(&1u16.to_le_bytes()); // Files
+        cab.extend_from_slice(&[0; 6]); // Flags, set ID and cabinet number
+
+        // The folder: where its data starts, the number of blocks, and LZX
+        // with a 32K window.
+        cab.extend_from_slice(&data_start.to_le_bytes());
+        cab.extend_from_slice(&1u16.to_le_bytes());
+        cab.extend_from_slice(&(0x0003u16 | 15 << 8).to_le_bytes());
+
+        // The file: its size, offset in the folder, folder, date, time and
+        // attributes, then its name.
+        cab.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        cab.extend_from_slice(&0u32.to_le_bytes());
+        cab.extend_from_slice(&0u16.to_le_bytes());
+        cab.extend_from_slice(&(40u16 << 9 | 1 << 5 | 1).to_le_bytes());
+        cab.extend_from_slice(&0u16.to_le_bytes());
+        cab.extend_from_slice(&0u16.to_le_bytes());
+        cab.extend_from_slice(name.as_bytes());
+        cab.push(0);
+
+        // The data block, without a checksum.
+        cab.extend_from_slice(&0u32.to_le_bytes());
+        cab.extend_from_slice(&(lzx.len() as u16).to_le_bytes());
+        cab.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        cab.extend_from_slice(&lzx);
+
+        assert_eq!(cab.len(), total as usize);
+        cab
+    }
+
+    #[test]
+    fn compressed_names() {
+        assert_eq!(compressed_name("ntdll.pdb"), "ntdll.pd_");
+        assert_eq!(compressed_name("notepad.exe"), "notepad.ex_");
+    }
+
+    #[test]
+    fn expand_mszip() {
+        let pdb = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0".repeat(64);
+
+        let mut builder = cab::CabinetBuilder::new();
+        builder
+            .add_folder(cab::CompressionType::MsZip)
+            .add_file("ntdll.pdb");
+        let mut writer = builder.build(Cursor::new(Vec::new())).unwrap();
+        while let Some(mut w) = writer.next_file().unwrap() {
+            w.write_all(&pdb).unwrap();
+        }
+        let cabinet = writer.finish().unwrap().into_inner();
+
+        assert!(cabinet.len() < pdb.len());
+        assert_eq!(expand_to_vec(cabinet).unwrap(), pdb);
+        assert!(expand_to_vec(pdb).is_err());
+    }
+
+    #[test]
+    fn expand_lzx() {
+        let pdb = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0".repeat(64);
+
+        let cabinet = lzx_cabinet("ntdll.pdb", &pdb);
+        assert_eq!(expand_to_vec(cabinet.clone()).unwrap(), pdb);
+
+        // A cabinet cut off part-way through the data.
+        let truncated = cabinet[..cabinet.len() - 16].to_vec();
+        assert!(expand_to_vec(truncated).is_err());
+    }
+}
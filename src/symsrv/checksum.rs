@@ -0,0 +1,173 @@
+//! Verification of downloaded PDBs against the `IMAGE_DEBUG_TYPE_PDBCHECKSUM`
+//! entries of the image that references them.
+use std::convert::TryInto;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+/// The expected checksum of a PDB, as recorded in the image that references it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PdbChecksum {
+    /// The name of the hash algorithm, e.g. `SHA256`.
+    pub algorithm: String,
+    /// The expected digest.
+    pub checksum: Vec<u8>,
+}
+
+impl PdbChecksum {
+    /// Parse the raw data of an `IMAGE_DEBUG_TYPE_PDBCHECKSUM` entry: a null
+    /// terminated UTF-8 algorithm name followed by the digest.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let null = data
+            .iter()
+            .position(|&x| x == 0)
+            .context("Failed to find null terminator in PDB checksum")?;
+
+        Ok(Self {
+            algorithm: std::str::from_utf8(&data[..null])?.to_string(),
+            checksum: data[null + 1..].to_vec(),
+        })
+    }
+
+    /// Compute the digest of `pdb` with this checksum's algorithm.
+    ///
+    /// Returns `None` if the algorithm is not one we know how to compute.
+    pub fn digest(&self, pdb: &[u8]) -> Option<Vec<u8>> {
+        // The checksum of a portable PDB is computed with its PDB ID zeroed out,
+        // since the ID is itself derived from the content.
+        let mut zeroed;
+        let pdb = match portable_pdb_id_offset(pdb) {
+            Some(offset) => {
+                zeroed = pdb.to_vec();
+                zeroed[offset..offset + PORTABLE_PDB_ID_LEN].fill(0);
+                &zeroed[..]
+            }
+            None => pdb,
+        };
+
+        match self.algorithm.as_str() {
+            "SHA256" => Some(Sha256::digest(pdb).to_vec()),
+            "SHA384" => Some(Sha384::digest(pdb).to_vec()),
+            "SHA512" => Some(Sha512::digest(pdb).to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Determine whether `pdb` matches this checksum. A checksum with an
+    /// unknown algorithm cannot be checked, and is treated as a match.
+    pub fn matches(&self, pdb: &[u8]) -> bool {
+        match self.digest(pdb) {
+            Some(digest) => digest == self.checksum,
+            None => true,
+        }
+    }
+}
+
+/// The length of the PDB ID at the start of the `#Pdb` stream.
+const PORTABLE_PDB_ID_LEN: usize = 20;
+
+/// Find the offset of the PDB ID in a portable PDB, i.e. the start of the
+/// `#Pdb` metadata stream. Returns `None` if `pdb` is not a portable PDB.
+fn portable_pdb_id_offset(pdb: &[u8]) -> Option<usize> {
+    let u16_at = |o: usize| Some(u16::from_le_bytes(pdb.get(o..o + 2)?.try_into().ok()?));
+    let u32_at = |o: usize| Some(u32::from_le_bytes(pdb.get(o..o + 4)?.try_into().ok()?));
+
+    // Metadata root: "BSJB", versions, reserved, then a padded version string.
+    if pdb.get(..4)? != b"BSJB" {
+        return None;
+    }
+
+    let version_len = u32_at(12)? as usize;
+    let mut offset = 16 + version_len;
+    let num_streams = u16_at(offset + 2)?;
+    offset += 4;
+
+    // Stream headers: offset, size, then a null terminated name padded to 4 bytes.
+    for _ in 0..num_streams {
+        let stream_offset = u32_at(offset)? as usize;
+        let name = pdb.get(offset + 8..)?;
+        let name_len = name.iter().position(|&x| x == 0)?;
+
+        if &name[..name_len] == b"#Pdb" {
+            pdb.get(stream_offset..stream_offset + PORTABLE_PDB_ID_LEN)?;
+            return Some(stream_offset);
+        }
+
+        offset += 8 + (name_len + 4) / 4 * 4;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build a skeletal portable PDB with a `#Pdb` stream holding `id`.
+    fn portable_pdb(id: [u8; 20]) -> Vec<u8> {
+        let mut pdb = b"BSJB".to_vec();
+        pdb.extend_from_slice(&1u16.to_le_bytes());
+        pdb.extend_from_slice(&1u16.to_le_bytes());
+        pdb.extend_from_slice(&0u32.to_le_bytes());
+        pdb.extend_from_slice(&12u32.to_le_bytes());
+        pdb.extend_from_slice(b"PDB v1.0\0\0\0\0");
+        pdb.extend_from_slice(&0u16.to_le_bytes());
+        pdb.extend_from_slice(&2u16.to_le_bytes());
+
+        // "#~" stream header, followed by the "#Pdb" stream header.
+        let data_start = pdb.len() as u32 + 12 + 16;
+        pdb.extend_from_slice(&(data_start + 20).to_le_bytes());
+        pdb.extend_from_slice(&4u32.to_le_bytes());
+        pdb.extend_from_slice(b"#~\0\0");
+        pdb.extend_from_slice(&data_start.to_le_bytes());
+        pdb.extend_from_slice(&20u32.to_le_bytes());
+        pdb.extend_from_slice(b"#Pdb\0\0\0\0");
+
+        pdb.extend_from_slice(&id);
+        pdb.extend_from_slice(b"tail");
+        pdb
+    }
+
+    #[test]
+    fn parse_checksum() {
+        let c = PdbChecksum::parse(b"SHA256\0\x01\x02\x03").unwrap();
+        assert_eq!(c.algorithm, "SHA256");
+        assert_eq!(c.checksum, vec![1, 2, 3]);
+
+        assert!(PdbChecksum::parse(b"SHA256").is_err());
+    }
+
+    #[test]
+    fn portable_checksum_ignores_id() {
+        let pdb = portable_pdb([0xAB; 20]);
+        let id_offset = portable_pdb_id_offset(&pdb).unwrap();
+        assert_eq!(&pdb[id_offset..id_offset + 20], &[0xAB; 20]);
+
+        let c = PdbChecksum {
+            algorithm: "SHA256".to_string(),
+            checksum: Sha256::digest(portable_pdb([0; 20])).to_vec(),
+        };
+        assert!(c.matches(&pdb));
+
+        let mut corrupt = pdb.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(!c.matches(&corrupt));
+    }
+
+    #[test]
+    fn native_checksum() {
+        let pdb = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0".to_vec();
+        let c = PdbChecksum {
+            algorithm: "SHA384".to_string(),
+            checksum: Sha384::digest(&pdb).to_vec(),
+        };
+        assert!(c.matches(&pdb));
+        assert!(!c.matches(b"something else"));
+
+        let unknown = PdbChecksum {
+            algorithm: "MD5".to_string(),
+            checksum: vec![],
+        };
+        assert!(unknown.matches(&pdb));
+    }
+}
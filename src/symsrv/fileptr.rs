@@ -0,0 +1,77 @@
+//! Parsing of the `file.ptr` redirections left by `symstore /p`, which point
+//! at a file stored elsewhere rather than holding a copy of it.
+use std::str::FromStr;
+
+use super::DownloadError;
+
+/// The contents of a `file.ptr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilePtr {
+    /// `PATH:<path>`, e.g. `PATH:\\server\share\ntdll.pdb` or `PATH:C:\Symbols\ntdll.pdb`.
+    Path(String),
+    /// `URL:<url>`, or a bare `http(s)://` URL.
+    Url(String),
+    /// `MSG:<text>`, a message explaining why the file isn't available.
+    Msg(String),
+}
+
+impl FromStr for FilePtr {
+    type Err = DownloadError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        // N.B: Only split on the first colon, since paths and URLs contain them too.
+        let (typ, value) = s
+            .split_once(':')
+            .ok_or_else(|| DownloadError::InvalidFilePtr(s.to_string()))?;
+
+        match typ {
+            t if t.eq_ignore_ascii_case("PATH") => Ok(FilePtr::Path(value.to_string())),
+            t if t.eq_ignore_ascii_case("URL") => Ok(FilePtr::Url(value.to_string())),
+            t if t.eq_ignore_ascii_case("MSG") => Ok(FilePtr::Msg(value.trim().to_string())),
+            t if t.eq_ignore_ascii_case("http") || t.eq_ignore_ascii_case("https") => {
+                Ok(FilePtr::Url(s.to_string()))
+            }
+            _ => Err(DownloadError::InvalidFilePtr(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_fileptr() {
+        assert_eq!(
+            FilePtr::from_str("PATH:C:\\Symbols\\ntdll.pdb\r\n").unwrap(),
+            FilePtr::Path("C:\\Symbols\\ntdll.pdb".to_string())
+        );
+        assert_eq!(
+            FilePtr::from_str("PATH:\\\\server\\share\\ntdll.pdb").unwrap(),
+            FilePtr::Path("\\\\server\\share\\ntdll.pdb".to_string())
+        );
+        assert_eq!(
+            FilePtr::from_str("URL:https://symbols.example.com/ntdll.pdb").unwrap(),
+            FilePtr::Url("https://symbols.example.com/ntdll.pdb".to_string())
+        );
+        assert_eq!(
+            FilePtr::from_str("https://symbols.example.com/ntdll.pdb").unwrap(),
+            FilePtr::Url("https://symbols.example.com/ntdll.pdb".to_string())
+        );
+        assert_eq!(
+            FilePtr::from_str("MSG: Symbols archived: contact the build team").unwrap(),
+            FilePtr::Msg("Symbols archived: contact the build team".to_string())
+        );
+
+        assert!(matches!(
+            FilePtr::from_str("FTP:ftp://symbols.example.com"),
+            Err(DownloadError::InvalidFilePtr(_))
+        ));
+        assert!(matches!(
+            FilePtr::from_str("\\\\server\\share\\ntdll.pdb"),
+            Err(DownloadError::InvalidFilePtr(_))
+        ));
+    }
+}
@@ -1,7 +1,17 @@
 pub mod blocking;
+pub mod cabinet;
+pub mod checksum;
+pub mod fileptr;
+pub mod negcache;
 pub mod nonblocking;
-
-use std::{path::PathBuf, str::FromStr};
+pub mod sympath;
+pub mod verify;
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 use thiserror::Error;
 
 /// Information about a symbol file resource.
@@ -9,17 +19,29 @@ use thiserror::Error;
 pub enum SymFileInfo {
     Exe(ExeInfo),
     Pdb(PdbInfo),
+    /// A .NET portable PDB. These are keyed on the GUID alone.
+    PortablePdb(PdbInfo),
+    /// A PDB referenced by a legacy `NB10` CodeView record.
+    Nb10(Nb10Info),
+    /// An ELF binary or its debug file.
+    Elf(ElfInfo),
+    /// A Mach-O binary or its dSYM DWARF file.
+    Mach(MachInfo),
     /// A raw symsrv-compatible hash.
     RawHash(String),
 }
 
-impl ToString for SymFileInfo {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for SymFileInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // The middle component of the resource's path on a symbol.
         match self {
-            SymFileInfo::Exe(i) => i.to_string(),
-            SymFileInfo::Pdb(i) => i.to_string(),
-            SymFileInfo::RawHash(h) => h.clone(),
+            SymFileInfo::Exe(i) => i.fmt(f),
+            SymFileInfo::Pdb(i) => i.fmt(f),
+            SymFileInfo::PortablePdb(i) => write!(f, "{:032X}FFFFFFFF", i.guid),
+            SymFileInfo::Nb10(i) => i.fmt(f),
+            SymFileInfo::Elf(i) => i.fmt(f),
+            SymFileInfo::Mach(i) => i.fmt(f),
+            SymFileInfo::RawHash(h) => f.write_str(h),
         }
     }
 }
@@ -31,9 +53,9 @@ pub struct ExeInfo {
     pub size: u32,
 }
 
-impl ToString for ExeInfo {
-    fn to_string(&self) -> String {
-        format!("{:08x}{:x}", self.timestamp, self.size)
+impl std::fmt::Display for ExeInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08x}{:x}", self.timestamp, self.size)
     }
 }
 
@@ -44,9 +66,72 @@ pub struct PdbInfo {
     pub age: u32,
 }
 
-impl ToString for PdbInfo {
-    fn to_string(&self) -> String {
-        format!("{:032X}{:x}", self.guid, self.age)
+impl std::fmt::Display for PdbInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:032X}{:x}", self.guid, self.age)
+    }
+}
+
+/// Legacy PDB file information relevant to a symbol server.
+///
+/// PDBs referenced by `NB10` CodeView records are keyed on a timestamp rather
+/// than a GUID.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Nb10Info {
+    pub timestamp: u32,
+    pub age: u32,
+}
+
+impl std::fmt::Display for Nb10Info {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08X}{:x}", self.timestamp, self.age)
+    }
+}
+
+/// ELF file information relevant to a symbol server.
+///
+/// ELF files are keyed on their GNU build-id, following the SSQP conventions.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ElfInfo {
+    pub build_id: Vec<u8>,
+    /// Whether this refers to the separate debug file (`_.debug`) rather than
+    /// the binary itself.
+    pub debug: bool,
+}
+
+impl std::fmt::Display for ElfInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = if self.debug {
+            "elf-buildid-sym-"
+        } else {
+            "elf-buildid-"
+        };
+
+        f.write_str(prefix)?;
+        self.build_id
+            .iter()
+            .try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+/// Mach-O file information relevant to a symbol server.
+///
+/// Mach-O files are keyed on the `LC_UUID` of each slice.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct MachInfo {
+    pub uuid: u128,
+    /// Whether this refers to the dSYM DWARF file (`_.dwarf`) rather than the
+    /// binary itself.
+    pub debug: bool,
+}
+
+impl std::fmt::Display for MachInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.debug {
+            write!(f, "mach-uuid-sym-{:032x}", self.uuid)
+        } else {
+            write!(f, "mach-uuid-{:032x}", self.uuid)
+        }
     }
 }
 
@@ -56,6 +141,35 @@ pub enum DownloadError {
     #[error("server returned 404 not found")]
     FileNotFound,
 
+    /// The server failed the request with something other than a 404, even
+    /// after any retries. It may still have the file.
+    #[error("server returned {0}")]
+    ServerError(reqwest::StatusCode),
+
+    /// The downloaded file did not match the checksum recorded in the image.
+    #[error("downloaded file does not match its {algorithm} checksum")]
+    ChecksumMismatch { algorithm: String },
+
+    /// The downloaded PDB is not the one that was requested.
+    #[error("downloaded PDB does not match {expected}: {actual}")]
+    SignatureMismatch { expected: String, actual: String },
+
+    /// A `file.ptr` redirection could not be understood.
+    #[error("invalid file.ptr contents: \"{0}\"")]
+    InvalidFilePtr(String),
+
+    /// The server left a message (`MSG:` in a `file.ptr`) in place of the file.
+    #[error("server returned a message instead of the file: {0}")]
+    Message(String),
+
+    /// The server rejected our credentials. Retrying it is pointless.
+    #[error("authentication with {server} failed: {reason}")]
+    Authentication { server: String, reason: String },
+
+    /// The server stopped sending data part-way through a request.
+    #[error("server sent nothing for {0:?}")]
+    Stalled(Duration),
+
     #[error("error requesting file")]
     Request(#[from] reqwest::Error),
 
@@ -63,6 +177,22 @@ pub enum DownloadError {
     Other(#[from] anyhow::Error),
 }
 
+impl DownloadError {
+    /// Determine whether the failure may be transient, and worth retrying.
+    ///
+    /// N.B: [`DownloadError::ServerError`] is not, since it is only returned
+    /// once the retries for the request have already run out.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::Stalled(_) => true,
+            DownloadError::Request(e) => {
+                e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+            }
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DownloadStatus {
     /// The symbol file already exists in the filesystem.
@@ -71,13 +201,97 @@ pub enum DownloadStatus {
     DownloadedOk,
 }
 
-/// A symbol server, defined by the user with the syntax `SRV*<cache_path>*<server_url>`.
+/// The protocol spoken by a symbol server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerKind {
+    /// A Microsoft symbol store, laid out as `<name>/<hash>/<name>`.
+    SymStore,
+    /// A debuginfod server, which serves ELF files by build-id from
+    /// `/buildid/<id>/debuginfo` and `/buildid/<id>/executable`.
+    Debuginfod,
+    /// A symbol store in a plain directory or UNC share, laid out like
+    /// [`ServerKind::SymStore`].
+    Filesystem,
+}
+
+/// A symbol server, defined by the user with the syntax `SRV*<cache_path>*<server_url>`,
+/// or `DEBUGINFOD*<cache_path>*<server_url>` for a debuginfod server.
+///
+/// These are usually resolved from a full symbol path by [`sympath::SymbolPath::servers`].
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SymSrvSpec {
-    /// The base URL for a symbol server, e.g: `https://msdl.microsoft.com/download/symbols`
+    /// The protocol spoken by the server.
+    pub kind: ServerKind,
+    /// The base URL for a symbol server, e.g: `https://msdl.microsoft.com/download/symbols`,
+    /// or the directory of a [`ServerKind::Filesystem`] store, e.g: `\\server\symbols`
     pub server_url: String,
     /// The base path for the local symbol cache, e.g: `C:\Symcache`
     pub cache_path: PathBuf,
+    /// Slower caches (e.g. a team share) consulted after `cache_path` and before
+    /// the server, nearest first. A hit in one of these is copied into every
+    /// nearer cache, and a fresh download is written to all of them.
+    pub upstream_caches: Vec<PathBuf>,
+    /// The maximum number of concurrent downloads from the server, set with a
+    /// trailing `*connections=<N>`. Unlimited if `None`.
+    pub connections: Option<usize>,
+    /// The maximum download rate from the server in bytes per second, set with
+    /// a trailing `*rate=<N>[K|M|G]`. Unlimited if `None`.
+    pub rate_limit: Option<u64>,
+    /// How long requests to the server may take.
+    pub timeouts: Timeouts,
+}
+
+impl SymSrvSpec {
+    /// Determine whether files are downloaded into a cache of the server's own,
+    /// rather than into the server itself, as with a directory explicitly made
+    /// its own cache (`cache*<dir>;<dir>`).
+    pub fn has_cache(&self) -> bool {
+        self.kind != ServerKind::Filesystem || Path::new(&self.server_url) != self.cache_path
+    }
+}
+
+#[cfg(test)]
+impl SymSrvSpec {
+    /// A server downloading straight into `cache_path`, with no upstream caches,
+    /// limits or custom timeouts.
+    pub fn test(
+        kind: ServerKind,
+        server_url: impl Into<String>,
+        cache_path: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            kind,
+            server_url: server_url.into(),
+            cache_path: cache_path.into(),
+            upstream_caches: vec![],
+            connections: None,
+            rate_limit: None,
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+/// How long requests to a symbol server may take before they are abandoned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// The longest to wait for a connection, set with a trailing `*connect_timeout=<secs>`.
+    pub connect: Duration,
+    /// The longest to wait for the server to send anything, whether the response
+    /// or more of its body, set with a trailing `*idle_timeout=<secs>`.
+    pub idle: Duration,
+    /// The longest a whole request may take, set with a trailing `*timeout=<secs>`.
+    /// Unlimited if `None`, since large PDBs can legitimately take a while.
+    pub overall: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(30),
+            idle: Duration::from_secs(60),
+            overall: None,
+        }
+    }
 }
 
 /// Determines if a symbol store uses a two-tier directory structure.
@@ -97,54 +311,60 @@ pub fn is_two_tier(cache_path: &std::path::Path) -> bool {
 ///
 /// For filenames shorter than 2 characters, returns the filename itself.
 pub fn two_tier_prefix(name: &str) -> String {
-    name.chars()
-        .take(2)
-        .collect::<String>()
-        .to_lowercase()
+    name.chars().take(2).collect::<String>().to_lowercase()
 }
 
 impl FromStr for SymSrvSpec {
     type Err = anyhow::Error;
 
     fn from_str(srv: &str) -> Result<Self, Self::Err> {
-        // Split the path out by asterisks.
-        let directives: Vec<&str> = srv.split('*').collect();
-
-        // Ensure that the path starts with `SRV*` - the only form we currently support.
-        match directives.first() {
-            // Simply exit the match statement if the directive is "SRV"
-            Some(x) => {
-                if x.eq_ignore_ascii_case("SRV") {
-                    if directives.len() != 3 {
-                        anyhow::bail!("Unsupported server string form; only 'SRV*<CACHE_PATH>*<SYMBOL_SERVER>' supported");
-                    }
-
-                    // Alright, the directive is of the proper form. Return the server and filepath.
-                    return Ok(SymSrvSpec {
-                        server_url: directives[2].to_string(),
-                        cache_path: directives[1].into(),
-                    });
-                }
-            }
-
-            None => {
-                anyhow::bail!("Unsupported server string form; only 'SRV*<CACHE_PATH>*<SYMBOL_SERVER>' supported");
-            }
-        };
+        // Parse the string as a symbol path, which must resolve to exactly one server.
+        let mut servers = SymSrvList::from_str(srv)?.0.into_vec();
+        if servers.len() != 1 {
+            anyhow::bail!("Expected a single symbol server in \"{srv}\"");
+        }
 
-        anyhow::bail!(
-            "Unsupported server string form; only 'SRV*<CACHE_PATH>*<SYMBOL_SERVER>' supported"
-        );
+        Ok(servers.remove(0))
     }
 }
 
 impl std::fmt::Display for SymSrvSpec {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "SRV*{}*{}", self.cache_path.display(), self.server_url)
+        let prefix = match self.kind {
+            ServerKind::SymStore | ServerKind::Filesystem => "SRV",
+            ServerKind::Debuginfod => "DEBUGINFOD",
+        };
+
+        write!(f, "{}*{}", prefix, self.cache_path.display())?;
+        for cache in &self.upstream_caches {
+            write!(f, "*{}", cache.display())?;
+        }
+
+        write!(f, "*{}", self.server_url)?;
+        if let Some(connections) = self.connections {
+            write!(f, "*connections={}", connections)?;
+        }
+        if let Some(rate) = self.rate_limit {
+            write!(f, "*rate={}", rate)?;
+        }
+
+        let default = Timeouts::default();
+        if self.timeouts.connect != default.connect {
+            write!(f, "*connect_timeout={}", self.timeouts.connect.as_secs())?;
+        }
+        if self.timeouts.idle != default.idle {
+            write!(f, "*idle_timeout={}", self.timeouts.idle.as_secs())?;
+        }
+        if let Some(overall) = self.timeouts.overall {
+            write!(f, "*timeout={}", overall.as_secs())?;
+        }
+
+        Ok(())
     }
 }
 
-/// A list of symbol servers, defined by the user with a semicolon-separated list.
+/// A list of symbol servers, defined by the user with a semicolon-separated
+/// symbol path (see [`sympath::SymbolPath`]).
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SymSrvList(pub Box<[SymSrvSpec]>);
 
@@ -152,17 +372,12 @@ impl FromStr for SymSrvList {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let server_list: Vec<&str> = s.split(';').collect();
-        if server_list.is_empty() {
-            anyhow::bail!("Invalid server string");
+        let servers = sympath::SymbolPath::from_str(s)?.servers()?;
+        if servers.is_empty() {
+            anyhow::bail!("Invalid server string; no symbol servers found");
         }
 
-        let vec = server_list
-            .into_iter()
-            .map(|symstr| symstr.parse::<SymSrvSpec>())
-            .collect::<anyhow::Result<Vec<_>>>()?;
-
-        Ok(SymSrvList(vec.into_boxed_slice()))
+        Ok(SymSrvList(servers.into_boxed_slice()))
     }
 }
 
@@ -175,22 +390,57 @@ mod test {
         assert_eq!(
             SymSrvSpec::from_str("SRV*C:\\Symbols*https://msdl.microsoft.com/download/symbols")
                 .unwrap(),
-            SymSrvSpec {
-                server_url: "https://msdl.microsoft.com/download/symbols".to_string(),
-                cache_path: "C:\\Symbols".into(),
-            }
+            SymSrvSpec::test(
+                ServerKind::SymStore,
+                "https://msdl.microsoft.com/download/symbols",
+                "C:\\Symbols"
+            )
         );
 
         assert_eq!(
             SymSrvSpec::from_str("srv*C:\\Symbols*https://msdl.microsoft.com/download/symbols")
                 .unwrap(),
-            SymSrvSpec {
-                server_url: "https://msdl.microsoft.com/download/symbols".to_string(),
-                cache_path: "C:\\Symbols".into(),
-            }
+            SymSrvSpec::test(
+                ServerKind::SymStore,
+                "https://msdl.microsoft.com/download/symbols",
+                "C:\\Symbols"
+            )
+        );
+
+        let spec = SymSrvSpec::from_str(
+            "SRV*C:\\Symbols*https://symbols.example.com*connections=8*connect_timeout=5",
+        )
+        .unwrap();
+        assert_eq!(spec.connections, Some(8));
+        assert_eq!(spec.timeouts.connect, Duration::from_secs(5));
+        assert_eq!(
+            spec.to_string(),
+            "SRV*C:\\Symbols*https://symbols.example.com*connections=8*connect_timeout=5"
         );
     }
 
+    #[test]
+    fn debuginfod_spec() {
+        let spec =
+            SymSrvSpec::from_str("DEBUGINFOD*/var/cache/sym*https://debuginfod.elfutils.org")
+                .unwrap();
+        assert_eq!(
+            spec,
+            SymSrvSpec::test(
+                ServerKind::Debuginfod,
+                "https://debuginfod.elfutils.org",
+                "/var/cache/sym"
+            )
+        );
+        assert_eq!(
+            spec.to_string(),
+            "DEBUGINFOD*/var/cache/sym*https://debuginfod.elfutils.org"
+        );
+
+        assert!(SymSrvSpec::from_str("DEBUGINFOD*").is_err());
+        assert!(SymSrvSpec::from_str("CACHE*/var/cache/sym").is_err());
+    }
+
     #[test]
     fn test_two_tier_prefix() {
         // Normal filenames
@@ -198,7 +448,7 @@ mod test {
         assert_eq!(two_tier_prefix("kernel32.pdb"), "ke");
         assert_eq!(two_tier_prefix("NTDLL.PDB"), "nt"); // Should be lowercase
         assert_eq!(two_tier_prefix("Kernel32.dll"), "ke");
-        
+
         // Edge cases - first two characters regardless of filename structure
         assert_eq!(two_tier_prefix("a.pdb"), "a."); // Only 1 char before dot, takes 'a.'
         assert_eq!(two_tier_prefix("ab"), "ab");
@@ -0,0 +1,195 @@
+//! A persistent record of the files symbol servers didn't have, so that
+//! repeated downloads don't ask for them again until the record expires.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use super::{SymFileInfo, SymSrvSpec};
+
+/// The file the negative cache is kept in, at the root of a symbol cache.
+pub const FILE_NAME: &str = "pdblister-misses.txt";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Files that symbol servers were found not to have, keyed on the server,
+/// the file name and its symbol server key.
+///
+/// Servers are identified by their kind and URL alone, so that changing how
+/// they are accessed (e.g. their connection limit) doesn't forget the misses.
+///
+/// The cache is stored as lines of `<unix time>\t<key>`, where the time is
+/// when the miss was recorded.
+#[derive(Debug)]
+pub struct NegativeCache {
+    path: PathBuf,
+    ttl: Duration,
+    /// Whether to ignore the recorded misses, while still recording new ones.
+    bypass: bool,
+    misses: Mutex<HashMap<String, u64>>,
+    /// The number of lookups skipped because of a recorded miss.
+    hits: AtomicU64,
+}
+
+impl NegativeCache {
+    /// Load the negative cache kept in the symbol cache at `cache`, forgetting
+    /// misses recorded more than `ttl` ago.
+    pub fn load(cache: &Path, ttl: Duration) -> anyhow::Result<Self> {
+        let path = cache.join(FILE_NAME);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context("failed to read negative cache"),
+        };
+
+        let expired = now().saturating_sub(ttl.as_secs());
+        let misses = contents
+            .lines()
+            .filter_map(|l| {
+                let (time, key) = l.split_once('\t')?;
+                Some((key.to_string(), time.parse::<u64>().ok()?))
+            })
+            .filter(|(_, time)| *time > expired)
+            .collect();
+
+        Ok(Self {
+            path,
+            ttl,
+            bypass: false,
+            misses: Mutex::new(misses),
+            hits: AtomicU64::new(0),
+        })
+    }
+
+    /// Ignore the recorded misses, asking the servers again. New misses are
+    /// still recorded.
+    pub fn with_bypass(mut self, bypass: bool) -> Self {
+        self.bypass = bypass;
+        self
+    }
+
+    fn key(srv: &SymSrvSpec, name: &str, info: &SymFileInfo) -> String {
+        format!("{:?}\t{}\t{name}\t{info}", srv.kind, srv.server_url)
+    }
+
+    /// Determine whether `srv` was recently found not to have `name`.
+    pub fn contains(&self, srv: &SymSrvSpec, name: &str, info: &SymFileInfo) -> bool {
+        if self.bypass {
+            return false;
+        }
+
+        let expired = now().saturating_sub(self.ttl.as_secs());
+        let misses = self.misses.lock().unwrap();
+        let hit = matches!(misses.get(&Self::key(srv, name, info)), Some(t) if *t > expired);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+
+        hit
+    }
+
+    /// Record that `srv` doesn't have `name`.
+    pub fn insert(&self, srv: &SymSrvSpec, name: &str, info: &SymFileInfo) {
+        let mut misses = self.misses.lock().unwrap();
+        misses.insert(Self::key(srv, name, info), now());
+    }
+
+    /// Forget any miss recorded for `name` on `srv`, e.g. once it has been found.
+    pub fn remove(&self, srv: &SymSrvSpec, name: &str, info: &SymFileInfo) {
+        let mut misses = self.misses.lock().unwrap();
+        misses.remove(&Self::key(srv, name, info));
+    }
+
+    /// The number of lookups skipped so far because of a recorded miss.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Write the negative cache back to the symbol cache.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let mut lines = self
+            .misses
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, time)| format!("{time}\t{key}\n"))
+            .collect::<Vec<_>>();
+        lines.sort();
+
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).context("failed to create symbol cache")?;
+        }
+
+        // N.B: Write a temporary copy first, so an interrupted save doesn't lose the cache.
+        let tmp = self.path.with_extension("txt.tmp");
+        std::fs::write(&tmp, lines.concat()).context("failed to write negative cache")?;
+        std::fs::rename(&tmp, &self.path).context("failed to write negative cache")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::symsrv::{ServerKind, Timeouts};
+
+    #[test]
+    fn record_misses() {
+        let cache = tempfile::tempdir().unwrap();
+        let srv = |url: &str| SymSrvSpec::test(ServerKind::SymStore, url, cache.path());
+        let (a, b) = (srv("https://a.example.com"), srv("https://b.example.com"));
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let misses = NegativeCache::load(cache.path(), day).unwrap();
+        assert!(!misses.contains(&a, "ntdll.pdb", &info));
+        misses.insert(&a, "ntdll.pdb", &info);
+        misses.insert(&a, "kernel32.pdb", &info);
+        misses.remove(&a, "kernel32.pdb", &info);
+        misses.save().unwrap();
+
+        let misses = NegativeCache::load(cache.path(), day).unwrap();
+        assert!(misses.contains(&a, "ntdll.pdb", &info));
+        assert!(!misses.contains(&b, "ntdll.pdb", &info));
+        assert!(!misses.contains(&a, "kernel32.pdb", &info));
+        assert_eq!(misses.hits(), 1);
+
+        // Tuning how the server is accessed doesn't forget its misses.
+        let tuned = SymSrvSpec {
+            connections: Some(8),
+            rate_limit: Some(512 * 1024),
+            timeouts: Timeouts {
+                idle: Duration::from_secs(5),
+                ..Default::default()
+            },
+            ..a.clone()
+        };
+        assert!(misses.contains(&tuned, "ntdll.pdb", &info));
+
+        let misses = misses.with_bypass(true);
+        assert!(!misses.contains(&a, "ntdll.pdb", &info));
+
+        // Old misses expire.
+        std::fs::write(
+            cache.path().join(FILE_NAME),
+            format!(
+                "{}\t{}\n",
+                now() - 2 * day.as_secs(),
+                NegativeCache::key(&a, "ntdll.pdb", &info)
+            ),
+        )
+        .unwrap();
+        let misses = NegativeCache::load(cache.path(), day).unwrap();
+        assert!(!misses.contains(&a, "ntdll.pdb", &info));
+    }
+}
@@ -2,19 +2,30 @@
 // #![warn(clippy::all)]
 // #![allow(clippy::needless_return)]
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 extern crate futures;
 extern crate indicatif;
 extern crate reqwest;
 extern crate tokio;
 
-use super::{DownloadError, DownloadStatus, SymFileInfo, SymSrvSpec, is_two_tier, two_tier_prefix};
+use super::{
+    cabinet,
+    checksum::PdbChecksum,
+    fileptr::FilePtr,
+    is_two_tier, two_tier_prefix,
+    verify::{self, Verification},
+    DownloadError, DownloadStatus, ServerKind, SymFileInfo, SymSrvSpec,
+};
 
 use anyhow::Context;
 use indicatif::{MultiProgress, ProgressBar};
 
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 mod style {
     use indicatif::ProgressStyle;
@@ -22,7 +33,7 @@ mod style {
     pub fn bar() -> ProgressStyle {
         ProgressStyle::default_bar()
             .template(
-                "[{elapsed_precise}] {bar:.cyan/blue} {bytes:>12}/{total_bytes:12} {wide_msg}",
+                "[{elapsed_precise}] {bar:.cyan/blue} {bytes:>12}/{total_bytes:12} {bytes_per_sec:>10} {wide_msg}",
             )
             .unwrap()
             .progress_chars("█▉▊▋▌▍▎▏  ")
@@ -35,6 +46,297 @@ mod style {
     }
 }
 
+/// How requests to a symbol server are retried after transient failures,
+/// i.e. 429 and 5xx responses, and connection errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts at each request, including the first.
+    pub max_attempts: u32,
+    /// The delay before the first retry. This doubles with each retry after it.
+    pub base_delay: Duration,
+    /// The longest we will wait between attempts, including when the server
+    /// asks for longer with `Retry-After`.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The delay before retry number `retry` (starting from 1), with jitter so
+    /// that concurrent downloads don't retry in lockstep.
+    fn backoff(&self, retry: u32) -> Duration {
+        use rand::Rng;
+
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << (retry - 1).min(16))
+            .min(self.max_delay);
+
+        // Wait somewhere between half and all of the exponential delay.
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+    }
+}
+
+/// The attempts left for a single download. Requests retried after a transient
+/// response and whole attempts retried after a failed transfer draw on the same
+/// budget, so that the retries don't multiply.
+#[derive(Debug)]
+struct RetryBudget {
+    max_attempts: u32,
+    attempts: AtomicU32,
+}
+
+impl RetryBudget {
+    fn new(policy: &RetryPolicy) -> Self {
+        Self {
+            max_attempts: policy.max_attempts,
+            attempts: AtomicU32::new(1),
+        }
+    }
+
+    /// Take another attempt, returning its retry number (starting from 1), or
+    /// `None` if the budget is spent.
+    fn take(&self) -> Option<u32> {
+        let attempt = self.attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        (attempt <= self.max_attempts).then(|| attempt - 1)
+    }
+}
+
+/// A token bucket capping the rate of the downloads that share it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// The cap, in bytes per second. This is also the size of the bucket, so
+    /// up to a second's worth may be sent in a burst.
+    bytes_per_sec: u64,
+    /// The bytes currently available, and when the bucket was last topped up.
+    /// This goes negative when more is taken than is available, which is then
+    /// paid back by waiting.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// A limiter capping downloads at `bytes_per_sec`.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` from the bucket, waiting until they have been paid for.
+    async fn consume(&self, bytes: usize) {
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (available, last) = &mut *bucket;
+
+            let now = Instant::now();
+            *available = (*available + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+
+            *available -= bytes as f64;
+            Duration::from_secs_f64((-*available / rate).max(0.0))
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Determine whether a response status is worth retrying.
+fn is_transient(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Parse the `Retry-After` header of `res`, in either delay-seconds or
+/// HTTP-date form.
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let value = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(std::time::SystemTime::now())
+            .ok(),
+    }
+}
+
+/// Append `suffix` to the file name at the end of `path`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// A temporary file that a single download is written to, named uniquely so
+/// that concurrent downloads never collide.
+///
+/// The file is removed when dropped, including when the download fails or is
+/// cancelled (e.g. on Ctrl-C), unless it was persisted or can be resumed later.
+struct TempFile {
+    path: PathBuf,
+    /// Where to move the file when dropped, for a later download to resume from.
+    resume_to: Option<PathBuf>,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// A new temporary file alongside `file_name`, e.g. `ntdll.pdb.3f2a9c1e.tmp`.
+    fn new(file_name: &Path) -> Self {
+        use rand::Rng;
+
+        let suffix = format!(".{:08x}.tmp", rand::thread_rng().gen::<u32>());
+        Self {
+            path: with_suffix(file_name, &suffix),
+            resume_to: None,
+            persisted: false,
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the temporary file into place as `file_name`.
+    async fn persist(mut self, file_name: &Path) -> std::io::Result<()> {
+        tokio::fs::rename(&self.path, file_name).await?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.persisted {
+            return;
+        }
+
+        // N.B: This must be synchronous, since we may be dropped mid-download.
+        // Silently ignore failures, e.g. if the file was never created.
+        match &self.resume_to {
+            Some(partial) => {
+                let _ = std::fs::rename(&self.path, partial);
+            }
+            None => {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+/// A download interrupted part-way, kept as `<file_name>.partial` so that it
+/// can be resumed with a `Range` request rather than started over.
+struct Partial {
+    /// The number of bytes already downloaded.
+    len: u64,
+    /// The `ETag` or `Last-Modified` of the original response, sent back as
+    /// `If-Range` so that the server sends the whole file if it has changed.
+    validator: String,
+}
+
+impl Partial {
+    /// Where an interrupted download of `file_name` is kept.
+    fn path(file_name: &Path) -> PathBuf {
+        with_suffix(file_name, ".partial")
+    }
+
+    /// The file holding the validator of the interrupted download of `file_name`.
+    fn validator_path(file_name: &Path) -> PathBuf {
+        with_suffix(file_name, ".partial.validator")
+    }
+
+    /// Claim the interrupted download of `file_name`, if any, by moving it to `tmp`.
+    ///
+    /// The validator is kept until the download completes or the file changes,
+    /// so the partial download can still be resumed if this attempt fails too.
+    ///
+    /// Returns `None` if there is nothing that can be resumed.
+    async fn claim(file_name: &Path, tmp: &Path) -> Option<Self> {
+        // N.B: The rename is atomic, so only one concurrent download gets the file.
+        tokio::fs::rename(Self::path(file_name), tmp).await.ok()?;
+
+        let len = tokio::fs::metadata(tmp).await.map(|m| m.len()).unwrap_or(0);
+        let validator = tokio::fs::read_to_string(Self::validator_path(file_name))
+            .await
+            .ok();
+
+        match validator {
+            Some(validator) if len != 0 => Some(Self { len, validator }),
+            _ => {
+                Self::forget(file_name).await;
+                None
+            }
+        }
+    }
+
+    /// Record `validator` for the download of `file_name`, so that it can be
+    /// resumed if interrupted.
+    async fn save(file_name: &Path, validator: &str) -> anyhow::Result<()> {
+        tokio::fs::write(Self::validator_path(file_name), validator)
+            .await
+            .context("failed to save download validator")
+    }
+
+    /// Forget the validator of the download of `file_name`, once it is no longer needed.
+    async fn forget(file_name: &Path) {
+        let _ = tokio::fs::remove_file(Self::validator_path(file_name)).await;
+    }
+}
+
+/// The validator of `res` that can be sent back as `If-Range`: a strong `ETag`,
+/// or failing that, `Last-Modified`.
+fn validator(res: &reqwest::Response) -> Option<String> {
+    use reqwest::header::{ETAG, LAST_MODIFIED};
+
+    let header = |name| res.headers().get(name)?.to_str().ok();
+
+    // N.B: Weak entity tags can't be used with `If-Range`.
+    header(ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(LAST_MODIFIED))
+        .map(str::to_string)
+}
+
+/// The offset of the first byte in the `Content-Range` of a 206 response,
+/// e.g. `bytes 1024-2047/2048`.
+fn content_range_start(res: &reqwest::Response) -> Option<u64> {
+    let range = res
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+
+    range
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
 enum RemoteFileType {
     /// HTTP-accessible URL (with a response already received)
     Url(reqwest::Response),
@@ -42,19 +344,174 @@ enum RemoteFileType {
     Path(String),
 }
 
+/// Build the URL of `hash` on a debuginfod server, which only serves ELF
+/// files keyed on their build-id.
+///
+/// Returns `None` if `hash` is not something a debuginfod server can serve.
+fn debuginfod_url(server_url: &str, hash: &str) -> Option<String> {
+    let (id, kind) = match hash.strip_prefix("elf-buildid-sym-") {
+        Some(id) => (id, "debuginfo"),
+        None => (hash.strip_prefix("elf-buildid-")?, "executable"),
+    };
+
+    Some(format!(
+        "{}/buildid/{}/{}",
+        server_url.trim_end_matches('/'),
+        id,
+        kind
+    ))
+}
+
+/// Check that `res` is not the symptom of missing or expired credentials: a
+/// 401, or (as Azure DevOps does) a login page served in place of the file.
+///
+/// N.B: A bare 403 is not enough, since stores backed by e.g. S3 return one for
+/// missing files. Only a 403 that comes with a login page counts.
+fn check_authentication(srv: &SymSrvSpec, res: &reqwest::Response) -> Result<(), DownloadError> {
+    let auth_error = |reason: String| {
+        Err(DownloadError::Authentication {
+            server: srv.server_url.clone(),
+            reason,
+        })
+    };
+
+    let status = res.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return auth_error(format!("server returned {status}"));
+    }
+
+    if status.is_success() || status == reqwest::StatusCode::FORBIDDEN {
+        if let Some(mime) = res.headers().get(reqwest::header::CONTENT_TYPE) {
+            let mime = match mime
+                .to_str()
+                .ok()
+                .and_then(|m| m.parse::<mime::Mime>().ok())
+            {
+                Some(mime) => mime,
+                None if status.is_success() => {
+                    return auth_error(format!("malformed Content-Type {mime:?}"))
+                }
+                None => return Ok(()),
+            };
+
+            if mime.subtype() == mime::HTML {
+                // Azure DevOps will do this if the authentication header isn't correct...
+                return auth_error(format!("server returned {status} with Content-Type {mime}"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `res` failed because the server doesn't have the file, i.e. with a 404.
+///
+/// Anything else is a problem with the server itself, which asking it for
+/// other copies of the file won't get around.
+fn check_not_found(res: &reqwest::Response) -> Result<(), DownloadError> {
+    match res.status() {
+        reqwest::StatusCode::NOT_FOUND => Ok(()),
+        status => Err(DownloadError::ServerError(status)),
+    }
+}
+
+/// Follow the `file.ptr` redirection in `contents`.
+async fn follow_fileptr(
+    symsrv: &SymSrv,
+    retries: &RetryBudget,
+    contents: &str,
+) -> Result<RemoteFileType, DownloadError> {
+    match contents.parse::<FilePtr>()? {
+        FilePtr::Path(path) => Ok(RemoteFileType::Path(path)),
+        FilePtr::Url(url) => {
+            let res = symsrv.get(&url, retries).await?;
+            if !res.status().is_success() {
+                // Attempt another server instead
+                check_not_found(&res)?;
+                return Err(DownloadError::FileNotFound);
+            }
+
+            Ok(RemoteFileType::Url(res))
+        }
+        FilePtr::Msg(msg) => Err(DownloadError::Message(msg)),
+    }
+}
+
+/// Look for `file` in the folder for `name` under `hash` in the symbol store
+/// at `store`. This is usually `name` itself, but may be e.g. a compressed copy.
+///
+/// This checks both single-tier and two-tier structures.
+fn find_in_store(store: &Path, name: &str, hash: &str, file: &str) -> Option<PathBuf> {
+    // Check two-tier structure first if index2.txt exists:
+    // "<cache_dir>/<prefix>/<name>/<hash>/<name>"
+    if is_two_tier(store) {
+        let path = store
+            .join(two_tier_prefix(name))
+            .join(name)
+            .join(hash)
+            .join(file);
+
+        if path.exists() {
+            return Some(path);
+        }
+    }
+
+    // Fall back to single-tier structure:
+    // "<cache_dir>/<name>/<hash>/<name>"
+    let path = store.join(name).join(hash).join(file);
+
+    path.exists().then_some(path)
+}
+
+/// Look for `name` under `hash` in the symbol cache at `cache`.
+fn find_in_cache(cache: &Path, name: &str, hash: &str) -> Option<PathBuf> {
+    find_in_store(cache, name, hash, name)
+}
+
+/// Copy the file at `src` into the symbol cache at `cache` as `name` under
+/// `hash`, using the cache's own layout.
+async fn copy_into_cache(src: &Path, cache: &Path, name: &str, hash: &str) -> anyhow::Result<()> {
+    let dir = if is_two_tier(cache) {
+        cache.join(two_tier_prefix(name)).join(name).join(hash)
+    } else {
+        cache.join(name).join(hash)
+    };
+    let file_name = dir.join(name);
+    if file_name.exists() {
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .context("failed to create symbol directory tree")?;
+
+    let tmp = TempFile::new(&file_name);
+    tokio::fs::copy(src, tmp.path())
+        .await
+        .context("failed to copy pdb")?;
+    tmp.persist(&file_name)
+        .await
+        .context("failed to rename pdb")?;
+
+    Ok(())
+}
+
 /// Attempt to download a single resource from a single symbol server.
 async fn download_single(
-    client: &reqwest::Client,
-    srv: &SymSrvSpec,
+    symsrv: &SymSrv,
     mp: Option<&MultiProgress>,
     name: &str,
     hash: &str,
-    use_two_tier: bool,
+    checksums: &[PdbChecksum],
+    slot: Option<&Slot>,
+    retries: &RetryBudget,
 ) -> Result<(DownloadStatus, PathBuf), DownloadError> {
+    let srv = &symsrv.spec;
+
     // Build the relative folder path, optionally with two-tier prefix.
     // Single-tier: "ntkrnlmp.pdb/32C1A669D5FFEFD41091F636CFDB6E991"
     // Two-tier:    "nt/ntkrnlmp.pdb/32C1A669D5FFEFD41091F636CFDB6E991"
-    let file_rel_folder = if use_two_tier {
+    let file_rel_folder = if is_two_tier(&srv.cache_path) {
         format!("{}/{}/{}", two_tier_prefix(name), name, hash)
     } else {
         format!("{}/{}", name, hash)
@@ -65,68 +522,125 @@ async fn download_single(
     // The path to the file's folder on the remote server
     let file_folder_url = format!("{}/{}", srv.server_url, file_rel_folder);
 
-    // Attempt to remove any existing temporary files first.
-    // Silently ignore failures since we don't care if this fails.
-    let file_name_tmp = file_name.with_extension("pdb.tmp");
-    let _ = tokio::fs::remove_file(&file_name_tmp).await;
-
     // Check to see if the file already exists. If so, skip it.
     if std::path::Path::new(&file_name).exists() {
-        return Ok((DownloadStatus::AlreadyExists, file_name.into()));
+        return Ok((DownloadStatus::AlreadyExists, file_name));
+    }
+
+    // Check the slower caches in the chain, copying a hit into every nearer one.
+    for (i, cache) in srv.upstream_caches.iter().enumerate() {
+        if let Some(path) = find_in_cache(cache, name, hash) {
+            for nearer in std::iter::once(&srv.cache_path).chain(&srv.upstream_caches[..i]) {
+                copy_into_cache(&path, nearer, name, hash).await?;
+            }
+
+            return Ok((DownloadStatus::AlreadyExists, file_name));
+        }
+    }
+
+    // Wait for our turn with the server, and only then for a shared job slot, so that
+    // a backlog for a busy server never holds slots the other servers could use.
+    let _slot = match slot {
+        Some(_) => None,
+        None => Some(symsrv.acquire().await),
+    };
+
+    // Pick up where any earlier attempt left off, if the server lets us. Put it
+    // back if this attempt fails before getting anywhere.
+    let mut tmp = TempFile::new(&file_name);
+    let partial = Partial::claim(&file_name, tmp.path()).await;
+    if partial.is_some() {
+        tmp.resume_to = Some(Partial::path(&file_name));
     }
 
+    // Whether the server gave us a CAB archive rather than the file itself.
+    let mut compressed = false;
+
     // Attempt to retrieve the file.
-    let remote_file = {
-        let pdb_req = client
-            .get::<&str>(&format!("{}/{}", file_folder_url, name))
-            .send()
-            .await?;
-        if pdb_req.status().is_success() {
-            if let Some(mime) = pdb_req.headers().get(reqwest::header::CONTENT_TYPE) {
-                let mime = mime
-                    .to_str()
-                    .expect("Content-Type header not a valid string")
-                    .parse::<mime::Mime>()
-                    .expect("Content-Type header not a valid MIME type");
-
-                if mime.subtype() == mime::HTML {
-                    // Azure DevOps will do this if the authentication header isn't correct...
-                    panic!(
-                        "Server {} returned an invalid Content-Type of {mime}",
-                        srv.server_url
-                    );
-                }
+    let remote_file = match srv.kind {
+        ServerKind::Filesystem => {
+            // The store is laid out like a cache, possibly compressed by `symstore /compress`.
+            let store = Path::new(&srv.server_url);
+            if let Some(path) = find_in_store(store, name, hash, name) {
+                RemoteFileType::Path(path.to_string_lossy().into_owned())
+            } else if let Some(path) =
+                find_in_store(store, name, hash, &cabinet::compressed_name(name))
+            {
+                compressed = true;
+                RemoteFileType::Path(path.to_string_lossy().into_owned())
+            } else {
+                let path = find_in_store(store, name, hash, "file.ptr")
+                    .ok_or(DownloadError::FileNotFound)?;
+                let fileptr = tokio::fs::read_to_string(path)
+                    .await
+                    .context("failed to read file.ptr")?;
+
+                follow_fileptr(symsrv, retries, &fileptr).await?
             }
+        }
+        ServerKind::Debuginfod => {
+            // Not something a debuginfod server knows about. Try another server.
+            let url = debuginfod_url(&srv.server_url, hash).ok_or(DownloadError::FileNotFound)?;
 
-            RemoteFileType::Url(pdb_req)
-        } else {
-            // Try a `file.ptr` redirection URL
-            let fileptr_req = client
-                .get::<&str>(&format!("{}/file.ptr", file_folder_url))
-                .send()
-                .await?;
-            if !fileptr_req.status().is_success() {
+            let req = symsrv.get_resume(&url, partial.as_ref(), retries).await?;
+            check_authentication(srv, &req)?;
+
+            if !req.status().is_success() {
                 // Attempt another server instead
+                check_not_found(&req)?;
                 Err(DownloadError::FileNotFound)?;
             }
 
-            let url = fileptr_req
-                .text()
-                .await
-                .context("failed to get file.ptr contents")?;
-
-            // FIXME: Would prefer not to unwrap the iterator results...
-            let mut url_iter = url.split(':');
-            let url_type = url_iter.next().unwrap();
-            let url = url_iter.next().unwrap();
-
-            match url_type {
-                "PATH" => RemoteFileType::Path(url.to_string()),
-                "MSG" => return Err(DownloadError::FileNotFound), // Try another server.
-                typ => {
-                    unimplemented!(
-                        "Unknown symbol redirection pointer type {typ}!\n{url_type}:{url}"
-                    );
+            RemoteFileType::Url(req)
+        }
+        ServerKind::SymStore => {
+            let pdb_req = symsrv
+                .get_resume(
+                    &format!("{}/{}", file_folder_url, name),
+                    partial.as_ref(),
+                    retries,
+                )
+                .await?;
+            check_authentication(srv, &pdb_req)?;
+
+            if pdb_req.status().is_success() {
+                RemoteFileType::Url(pdb_req)
+            } else {
+                check_not_found(&pdb_req)?;
+
+                // Try the compressed copy left behind by `symstore /compress`
+                let cab_req = symsrv
+                    .get(
+                        &format!("{}/{}", file_folder_url, cabinet::compressed_name(name)),
+                        retries,
+                    )
+                    .await?;
+                check_authentication(srv, &cab_req)?;
+
+                if cab_req.status().is_success() {
+                    compressed = true;
+                    RemoteFileType::Url(cab_req)
+                } else {
+                    check_not_found(&cab_req)?;
+
+                    // Try a `file.ptr` redirection URL
+                    let fileptr_req = symsrv
+                        .get(&format!("{}/file.ptr", file_folder_url), retries)
+                        .await?;
+                    check_authentication(srv, &fileptr_req)?;
+
+                    if !fileptr_req.status().is_success() {
+                        // Attempt another server instead
+                        check_not_found(&fileptr_req)?;
+                        Err(DownloadError::FileNotFound)?;
+                    }
+
+                    let fileptr = fileptr_req
+                        .text()
+                        .await
+                        .context("failed to get file.ptr contents")?;
+
+                    follow_fileptr(symsrv, retries, &fileptr).await?
                 }
             }
         }
@@ -139,6 +653,24 @@ async fn download_single(
 
     match remote_file {
         RemoteFileType::Url(mut res) => {
+            // Only a 206 carries on from the partial download. Anything else means the
+            // server ignored the range, or the file changed since, so start over.
+            let (offset, validator) = match &partial {
+                Some(p) if res.status() == reqwest::StatusCode::PARTIAL_CONTENT => {
+                    if content_range_start(&res) != Some(p.len) {
+                        // Don't try to resume this again.
+                        tmp.resume_to = None;
+                        Partial::forget(&file_name).await;
+                        return Err(
+                            anyhow::anyhow!("server resumed download at the wrong offset").into(),
+                        );
+                    }
+
+                    (p.len, Some(p.validator.clone()))
+                }
+                _ => (0, validator(&res)),
+            };
+
             // N.B: If the server sends us a content-length header, use it to display a progress bar.
             // Otherwise, just display a spinner progress bar.
             // TODO: Should have the library user provide a trait that allows us to create a progress bar
@@ -146,8 +678,9 @@ async fn download_single(
             let dl_pb = if let Some(m) = mp {
                 let dl_pb = match res.content_length() {
                     Some(len) => {
-                        let dl_pb = m.add(ProgressBar::new(len));
+                        let dl_pb = m.add(ProgressBar::new(offset + len));
                         dl_pb.set_style(style::bar());
+                        dl_pb.set_position(offset);
 
                         dl_pb
                     }
@@ -167,28 +700,65 @@ async fn download_single(
                 None
             };
 
-            // Create the output file.
-            let mut file = tokio::fs::File::create(&file_name_tmp)
-                .await
-                .context("failed to create output pdb")?;
+            // Open the output file, appending to the partial download if resuming.
+            let mut file = if offset != 0 {
+                tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(tmp.path())
+                    .await
+                    .context("failed to open partial pdb")?
+            } else {
+                tokio::fs::File::create(tmp.path())
+                    .await
+                    .context("failed to create output pdb")?
+            };
+
+            // Keep the download for a later attempt to resume if this one is interrupted,
+            // which is only possible if the server gave us a validator.
+            match validator {
+                Some(validator) => {
+                    Partial::save(&file_name, &validator).await?;
+                    tmp.resume_to = Some(Partial::path(&file_name));
+                }
+                None => {
+                    tmp.resume_to = None;
+                    Partial::forget(&file_name).await;
+                }
+            }
 
             // N.B: We use this in lieu of tokio::io::copy so we can update the download progress.
-            while let Some(chunk) = res.chunk().await.context("failed to download pdb chunk")? {
+            let idle = srv.timeouts.idle;
+            loop {
+                let chunk = match tokio::time::timeout(idle, res.chunk()).await {
+                    Ok(Ok(Some(chunk))) => Ok(chunk),
+                    Ok(Ok(None)) => break,
+                    Ok(Err(e)) => Err(DownloadError::Request(e)),
+                    Err(_) => Err(DownloadError::Stalled(idle)),
+                };
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        // Keep what we have so far for the next attempt to resume from.
+                        let _ = file.flush().await;
+                        return Err(e);
+                    }
+                };
+
+                symsrv.throttle(chunk.len()).await;
                 if let Some(dl_pb) = &dl_pb {
                     dl_pb.inc(chunk.len() as u64);
                 }
 
-                file.write(&chunk)
+                file.write_all(&chunk)
                     .await
                     .context("failed to write pdb chunk")?;
             }
 
-            // Rename the temporary copy to the final name
-            tokio::fs::rename(&file_name_tmp, &file_name)
-                .await
-                .context("failed to rename pdb")?;
+            file.flush().await.context("failed to write pdb")?;
 
-            Ok((DownloadStatus::DownloadedOk, file_name.into()))
+            // The download is complete, so there is nothing left to resume.
+            tmp.resume_to = None;
+            Partial::forget(&file_name).await;
         }
 
         RemoteFileType::Path(path) => {
@@ -212,35 +782,114 @@ async fn download_single(
                 None
             };
 
-            // Create the output file.
-            let mut file = tokio::fs::File::create(&file_name_tmp)
+            // Create the output file. Copies from a path are never resumed, so this
+            // replaces any partial download.
+            tmp.resume_to = None;
+            Partial::forget(&file_name).await;
+            let mut file = tokio::fs::File::create(tmp.path())
                 .await
                 .context("failed to create output pdb")?;
 
-            if let Some(dl_pb) = dl_pb {
-                tokio::io::copy(&mut dl_pb.wrap_async_read(remote_file), &mut file)
+            // N.B: We use this in lieu of tokio::io::copy so we can apply the rate limits.
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let len = tokio::time::timeout(srv.timeouts.idle, remote_file.read(&mut buf))
                     .await
+                    .map_err(|_| DownloadError::Stalled(srv.timeouts.idle))?
                     .context("failed to copy pdb")?;
-            } else {
-                tokio::io::copy(&mut remote_file, &mut file)
+                if len == 0 {
+                    break;
+                }
+
+                symsrv.throttle(len).await;
+                if let Some(dl_pb) = &dl_pb {
+                    dl_pb.inc(len as u64);
+                }
+
+                file.write_all(&buf[..len])
                     .await
                     .context("failed to copy pdb")?;
             }
 
-            // Rename the temporary copy to the final name
-            tokio::fs::rename(&file_name_tmp, &file_name)
-                .await
-                .context("failed to rename pdb")?;
+            file.flush().await.context("failed to copy pdb")?;
+        }
+    }
+
+    // Expand compressed files into a new temporary file, so they are checked and cached
+    // under the real name. The cabinet is removed when it's replaced.
+    if compressed {
+        let expanded = TempFile::new(&file_name);
+        let (src, dst) = (tmp.path().to_path_buf(), expanded.path().to_path_buf());
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let cabinet = std::fs::File::open(src).context("failed to read cabinet")?;
+            let mut out = std::io::BufWriter::new(
+                std::fs::File::create(dst).context("failed to create expanded pdb")?,
+            );
+            cabinet::expand(std::io::BufReader::new(cabinet), &mut out)?;
+            std::io::Write::flush(&mut out).context("failed to write expanded pdb")
+        })
+        .await
+        .context("failed to expand cabinet")??;
 
-            Ok((DownloadStatus::DownloadedOk, file_name.into()))
+        tmp = expanded;
+    }
+
+    // Make sure we got the file the image expects before putting it in place.
+    if !checksums.is_empty() {
+        let pdb = tokio::fs::read(tmp.path())
+            .await
+            .context("failed to read pdb for checksum")?;
+
+        if let Some(c) = checksums.iter().find(|c| !c.matches(&pdb)) {
+            return Err(DownloadError::ChecksumMismatch {
+                algorithm: c.algorithm.clone(),
+            });
         }
     }
+
+    // Make sure the server gave us the PDB we asked for, if requested.
+    if symsrv.verify {
+        let path = tmp.path().to_path_buf();
+        let expected = hash.to_string();
+        let result = tokio::task::spawn_blocking(move || verify::verify_pdb(&path, &expected))
+            .await
+            .context("failed to verify pdb")??;
+
+        let actual = match result {
+            Verification::Match | Verification::Unsupported => None,
+            Verification::Mismatch { actual } => Some(actual),
+            Verification::Corrupt(e) => Some(e),
+        };
+
+        // Keep the rejected file aside for inspection, where it's never taken for a
+        // cache hit. This is best-effort, since the download has failed either way.
+        if let Some(actual) = actual {
+            let _ = tmp.persist(&with_suffix(&file_name, ".mismatch")).await;
+            return Err(DownloadError::SignatureMismatch {
+                expected: hash.to_string(),
+                actual,
+            });
+        }
+    }
+
+    // Rename the temporary copy to the final name
+    tmp.persist(&file_name)
+        .await
+        .context("failed to rename pdb")?;
+
+    // Populate the slower caches too. This is best-effort, since e.g. a read-only
+    // team share shouldn't fail a download that already landed in the local cache.
+    for cache in &srv.upstream_caches {
+        let _ = copy_into_cache(&file_name, cache, name, hash).await;
+    }
+
+    Ok((DownloadStatus::DownloadedOk, file_name))
 }
 
 /// Connect to Azure and authenticate requests using a PAT.
 ///
 /// Reference: https://docs.microsoft.com/en-us/azure/devops/organizations/accounts/use-personal-access-tokens-to-authenticate?view=azure-devops&tabs=Windows
-fn connect_pat(token: &str) -> anyhow::Result<reqwest::Client> {
+fn connect_pat(builder: reqwest::ClientBuilder, token: &str) -> anyhow::Result<reqwest::Client> {
     use reqwest::header;
 
     // N.B: According to ADO documentation, the token needs to be preceded by an arbitrary
@@ -251,16 +900,24 @@ fn connect_pat(token: &str) -> anyhow::Result<reqwest::Client> {
     let auth_value = header::HeaderValue::from_str(&format!("Basic {b64}"))?;
     headers.insert(header::AUTHORIZATION, auth_value);
 
-    Ok(reqwest::Client::builder()
-        .default_headers(headers)
-        .https_only(true)
-        .build()?)
+    Ok(builder.default_headers(headers).https_only(true).build()?)
 }
 
 fn connect_server(srv: &SymSrvSpec) -> anyhow::Result<reqwest::Client> {
     // Determine if the URL is a known URL that requires OAuth2 authorization.
     use url::{Host, Url};
 
+    // N.B: reqwest has no timeout for idle reads, so we apply `srv.timeouts.idle` ourselves.
+    let mut builder = reqwest::Client::builder().connect_timeout(srv.timeouts.connect);
+    if let Some(timeout) = srv.timeouts.overall {
+        builder = builder.timeout(timeout);
+    }
+
+    // Stores on the filesystem only need a client to follow `file.ptr` URLs.
+    if srv.kind == ServerKind::Filesystem {
+        return Ok(builder.build()?);
+    }
+
     let url = Url::parse(&srv.server_url)
         .context(format!("invalid server URL: \"{}\"", &srv.server_url))?;
     match url.host() {
@@ -274,26 +931,46 @@ fn connect_server(srv: &SymSrvSpec) -> anyhow::Result<reqwest::Client> {
                         .map(|p| p.to_string())
                         .context("ADO requires a PAT for authentication")?;
 
-                    Ok(connect_pat(&pat)?)
+                    Ok(connect_pat(builder, &pat)?)
                 }
 
                 _ => {
                     // Unknown URL; return a fresh client.
-                    Ok(reqwest::Client::new())
+                    Ok(builder.build()?)
                 }
             }
         }
         Some(Host::Ipv4(_) | Host::Ipv6(_)) | None => {
             // Just return a new client.
-            Ok(reqwest::Client::new())
+            Ok(builder.build()?)
         }
     }
 }
 
+/// Permission to download from a server under its connection limit and the
+/// shared job limit, given back when dropped.
+#[derive(Debug)]
+pub struct Slot {
+    _connection: Option<OwnedSemaphorePermit>,
+    _job: Option<OwnedSemaphorePermit>,
+}
+
 #[derive(Debug, Clone)]
 pub struct SymSrv {
     spec: SymSrvSpec,
     client: reqwest::Client,
+    verify: bool,
+    retry: RetryPolicy,
+    /// The number of requests retried so far, shared between clones.
+    retries: Arc<AtomicU64>,
+    /// Limits concurrent downloads from this server, per `SymSrvSpec::connections`.
+    connections: Option<Arc<Semaphore>>,
+    /// Limits concurrent downloads across every server sharing it.
+    jobs: Option<Arc<Semaphore>>,
+    /// Limits the download rate from this server, per `SymSrvSpec::rate_limit`.
+    rate_limit: Option<Arc<RateLimiter>>,
+    /// Limits the download rate across every server sharing it.
+    global_rate_limit: Option<Arc<RateLimiter>>,
 }
 
 impl SymSrv {
@@ -301,10 +978,206 @@ impl SymSrv {
     pub fn connect(spec: SymSrvSpec) -> anyhow::Result<Self> {
         Ok(Self {
             client: connect_server(&spec)?,
+            connections: spec.connections.map(|n| Arc::new(Semaphore::new(n))),
+            rate_limit: spec.rate_limit.map(|r| Arc::new(RateLimiter::new(r))),
             spec,
+            verify: false,
+            retry: RetryPolicy::default(),
+            retries: Arc::new(AtomicU64::new(0)),
+            jobs: None,
+            global_rate_limit: None,
+        })
+    }
+
+    /// Share the limit `jobs` on concurrent downloads with other servers.
+    ///
+    /// N.B: Waiters on a semaphore are served in order, so downloads get their
+    /// turn in the order they asked for it.
+    pub fn with_job_limit(mut self, jobs: Arc<Semaphore>) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Cap the download rate across every server sharing `limit`, on top of
+    /// the server's own limit.
+    pub fn with_rate_limit(mut self, limit: Arc<RateLimiter>) -> Self {
+        self.global_rate_limit = Some(limit);
+        self
+    }
+
+    /// Wait until `bytes` more may be downloaded under the rate limits.
+    async fn throttle(&self, bytes: usize) {
+        for limit in self.rate_limit.iter().chain(&self.global_rate_limit) {
+            limit.consume(bytes).await;
+        }
+    }
+
+    /// Wait until a download may start, first for the server's own limit and
+    /// then for the shared one. The download may proceed while the slot is held.
+    async fn acquire(&self) -> Slot {
+        let connection = match &self.connections {
+            Some(s) => s.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        let job = match &self.jobs {
+            Some(s) => s.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+
+        Slot {
+            _connection: connection,
+            _job: job,
+        }
+    }
+
+    /// Take a slot to download from this server in, if both the server's own
+    /// limit and the shared one have room right now.
+    pub fn try_acquire(&self) -> Option<Slot> {
+        let connection = match &self.connections {
+            Some(s) => Some(s.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        let job = match &self.jobs {
+            Some(s) => Some(s.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+
+        Some(Slot {
+            _connection: connection,
+            _job: job,
         })
     }
 
+    /// Retry requests that fail transiently according to `retry`.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The number of requests to this server that have been retried.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Send a GET request for `url`, retrying transient failures according to
+    /// the retry policy.
+    ///
+    /// If the server is still failing after the last attempt, its response is
+    /// returned as-is.
+    async fn get(
+        &self,
+        url: &str,
+        retries: &RetryBudget,
+    ) -> Result<reqwest::Response, DownloadError> {
+        self.get_range(url, None, retries).await
+    }
+
+    /// Send a GET request for `url`, asking only for the part after `partial`
+    /// unless the file has changed since it was downloaded.
+    ///
+    /// If the server can't satisfy the range, the whole file is requested instead.
+    async fn get_resume(
+        &self,
+        url: &str,
+        partial: Option<&Partial>,
+        retries: &RetryBudget,
+    ) -> Result<reqwest::Response, DownloadError> {
+        if partial.is_some() {
+            let res = self.get_range(url, partial, retries).await?;
+            if res.status() != reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+                return Ok(res);
+            }
+        }
+
+        self.get_range(url, None, retries).await
+    }
+
+    /// Send a GET request for `url`, resuming `partial` if given, and retrying
+    /// transient responses while `retries` has attempts left.
+    ///
+    /// A server that doesn't respond within the idle timeout is treated as
+    /// having stalled. Failures to get a response at all are returned as-is,
+    /// for `download` to retry along with failures part-way through the body.
+    async fn get_range(
+        &self,
+        url: &str,
+        partial: Option<&Partial>,
+        retries: &RetryBudget,
+    ) -> Result<reqwest::Response, DownloadError> {
+        use reqwest::header::{IF_RANGE, RANGE};
+
+        loop {
+            let mut req = self.client.get(url);
+            if let Some(p) = partial {
+                req = req
+                    .header(RANGE, format!("bytes={}-", p.len))
+                    .header(IF_RANGE, &p.validator);
+            }
+
+            let idle = self.spec.timeouts.idle;
+            let res = match tokio::time::timeout(idle, req.send()).await {
+                Ok(res) => res.map_err(DownloadError::from),
+                Err(_) => Err(DownloadError::Stalled(idle)),
+            };
+            let delay = match &res {
+                Ok(r) if is_transient(r.status()) => retries
+                    .take()
+                    .map(|retry| retry_after(r).unwrap_or_else(|| self.retry.backoff(retry))),
+                _ => None,
+            };
+
+            match delay {
+                Some(delay) => {
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(delay.min(self.retry.max_delay)).await;
+                }
+                None => return res,
+            }
+        }
+    }
+
+    /// Download a single file, retrying failed connections and transfers that
+    /// are cut off or stall according to the retry policy. Each attempt picks
+    /// up where the last left off if it can.
+    ///
+    /// The retry policy's `max_attempts` bounds every request made for the
+    /// file, including retries of transient responses.
+    async fn download(
+        &self,
+        mp: Option<&MultiProgress>,
+        name: &str,
+        info: &SymFileInfo,
+        checksums: &[PdbChecksum],
+        slot: Option<Slot>,
+    ) -> Result<(DownloadStatus, PathBuf), DownloadError> {
+        let hash = info.to_string();
+
+        let retries = RetryBudget::new(&self.retry);
+        loop {
+            let slot = slot.as_ref();
+            let res = download_single(self, mp, name, &hash, checksums, slot, &retries).await;
+
+            match res {
+                Err(e) if e.is_retryable() => match retries.take() {
+                    Some(retry) => {
+                        self.retries.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(self.retry.backoff(retry)).await;
+                    }
+                    None => return Err(e),
+                },
+                res => return res,
+            }
+        }
+    }
+
+    /// Check that downloaded PDBs carry the GUID and age they were requested
+    /// with, rejecting any that don't. A rejected file is kept beside where it
+    /// would have been cached, as `<name>.mismatch`.
+    pub fn with_verification(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
     /// Retrieve the associated server specification from this connection.
     pub fn spec(&self) -> SymSrvSpec {
         self.spec.clone()
@@ -312,33 +1185,18 @@ impl SymSrv {
 
     /// Attempt to find a single file in the symbol store associated with this context.
     ///
-    /// If the file is found, its cache path will be returned.
-    /// This method checks both single-tier and two-tier structures.
+    /// Each cache in the chain is consulted in turn, nearest first, and the path of
+    /// the first copy found is returned. This method checks both single-tier and
+    /// two-tier structures.
+    ///
+    /// N.B: A copy found in a slower cache is not brought into the nearer ones; the
+    /// `download_file` family of methods does that.
     pub fn find_file(&self, name: &str, info: &SymFileInfo) -> Option<PathBuf> {
         let hash = info.to_string();
 
-        // Check two-tier structure first if index2.txt exists:
-        // "<cache_dir>/<prefix>/<name>/<hash>/<name>"
-        if is_two_tier(&self.spec.cache_path) {
-            let path = PathBuf::from(&self.spec.cache_path)
-                .join(two_tier_prefix(name))
-                .join(name)
-                .join(&hash)
-                .join(name);
-
-            if path.exists() {
-                return Some(path);
-            }
-        }
-
-        // Fall back to single-tier structure:
-        // "<cache_dir>/<name>/<hash>/<name>"
-        let path = PathBuf::from(&self.spec.cache_path)
-            .join(name)
-            .join(hash)
-            .join(name);
-
-        path.exists().then_some(path)
+        std::iter::once(&self.spec.cache_path)
+            .chain(&self.spec.upstream_caches)
+            .find_map(|cache| find_in_cache(cache, name, &hash))
     }
 
     /// Download and cache a single file in the symbol store associated with this context,
@@ -351,14 +1209,25 @@ impl SymSrv {
         name: &str,
         info: &SymFileInfo,
     ) -> Result<PathBuf, DownloadError> {
-        let hash = info.to_string();
-        let use_two_tier = is_two_tier(&self.spec.cache_path);
-
-        download_single(&self.client, &self.spec, None, name, &hash, use_two_tier)
+        self.download(None, name, info, &[], None)
             .await
             .map(|r| r.1)
     }
 
+    /// Download and cache a single file in the symbol store associated with this context,
+    /// and then return its path on the local system.
+    ///
+    /// The downloaded file is checked against `checksums` (typically taken from the
+    /// image that references it), and is discarded if it does not match.
+    pub async fn download_file_checked(
+        &self,
+        name: &str,
+        info: &SymFileInfo,
+        checksums: &[PdbChecksum],
+    ) -> Result<(DownloadStatus, PathBuf), DownloadError> {
+        self.download(None, name, info, checksums, None).await
+    }
+
     /// Download (displaying progress) and cache a single file in the symbol store associated with this context,
     /// and then return its path on the local system.
     ///
@@ -369,12 +1238,854 @@ impl SymSrv {
         name: &str,
         info: &SymFileInfo,
         mp: &MultiProgress,
-    ) -> Result<PathBuf, DownloadError> {
-        let hash = info.to_string();
-        let use_two_tier = is_two_tier(&self.spec.cache_path);
+    ) -> Result<(DownloadStatus, PathBuf), DownloadError> {
+        self.download(Some(mp), name, info, &[], None).await
+    }
+
+    /// Like `download_file_progress`, but in a slot already taken with
+    /// `try_acquire` rather than waiting for one. The slot is held until the
+    /// download is done, retries included.
+    pub async fn download_file_in_slot(
+        &self,
+        name: &str,
+        info: &SymFileInfo,
+        mp: &MultiProgress,
+        slot: Slot,
+    ) -> Result<(DownloadStatus, PathBuf), DownloadError> {
+        self.download(Some(mp), name, info, &[], Some(slot)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    use crate::symsrv::Timeouts;
+
+    /// Build a raw HTTP response.
+    fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut res = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\n", body.len());
+        for (name, value) in headers {
+            res += &format!("{name}: {value}\r\n");
+        }
+        res += "Connection: close\r\n\r\n";
+
+        [res.as_bytes(), body].concat()
+    }
+
+    /// Serve HTTP on a local port, answering each request (given as its raw
+    /// text) with the raw response `handler` returns. Returns the base URL of
+    /// the server.
+    async fn serve_with(handler: impl Fn(&str) -> Vec<u8> + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = sock.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+
+                let res = handler(&String::from_utf8_lossy(&req));
+                let _ = sock.write_all(&res).await;
+            }
+        });
+
+        format!("http://{addr}")
+    }
+
+    /// Serve `files` (path, contents) over HTTP on a local port, answering
+    /// anything else with a 404. Returns the base URL of the server.
+    async fn serve(files: Vec<(&'static str, Vec<u8>)>) -> String {
+        serve_with(move |req| {
+            let path = req.split(' ').nth(1).unwrap_or_default();
+            match files.iter().find(|(p, _)| *p == path) {
+                Some((_, body)) => response("200 OK", &[], body),
+                None => response("404 Not Found", &[], b""),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn debuginfod_download() {
+        let url = serve(vec![
+            ("/buildid/15dfff32/debuginfo", b"\x7fELF debuginfo".to_vec()),
+            (
+                "/buildid/15dfff32/executable",
+                b"\x7fELF executable".to_vec(),
+            ),
+        ])
+        .await;
+        let cache = tempfile::tempdir().unwrap();
+
+        let srv =
+            SymSrv::connect(SymSrvSpec::test(ServerKind::Debuginfod, url, cache.path())).unwrap();
+
+        let debug = SymFileInfo::RawHash("elf-buildid-sym-15dfff32".to_string());
+        let path = srv.download_file("_.debug", &debug).await.unwrap();
+        assert_eq!(
+            path,
+            cache
+                .path()
+                .join("_.debug/elf-buildid-sym-15dfff32/_.debug")
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"\x7fELF debuginfo");
+        assert_eq!(srv.find_file("_.debug", &debug), Some(path));
+
+        let exe = SymFileInfo::RawHash("elf-buildid-15dfff32".to_string());
+        let path = srv.download_file("ls", &exe).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"\x7fELF executable");
+
+        // Unknown build-ids and non-ELF keys are left for other servers.
+        let missing = SymFileInfo::RawHash("elf-buildid-sym-deadbeef".to_string());
+        assert!(matches!(
+            srv.download_file("_.debug", &missing).await,
+            Err(DownloadError::FileNotFound)
+        ));
+        let pdb = SymFileInfo::RawHash("7639032E274848798FD80F9F61D5371B1".to_string());
+        assert!(matches!(
+            srv.download_file("w32.pdb", &pdb).await,
+            Err(DownloadError::FileNotFound)
+        ));
+
+        // A failing server is not mistaken for one that doesn't have the file.
+        let url = serve_with(|_| response("503 Service Unavailable", &[], b"")).await;
+        let srv = SymSrv::connect(SymSrvSpec {
+            server_url: url,
+            ..srv.spec()
+        })
+        .unwrap()
+        .with_retry_policy(RetryPolicy::none());
+        assert!(matches!(
+            srv.download_file("_.debug", &missing).await,
+            Err(DownloadError::ServerError(
+                reqwest::StatusCode::SERVICE_UNAVAILABLE
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn symstore_compressed_download() {
+        use std::io::Write;
+
+        let pdb = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0".repeat(16);
+        let mut builder = cab::CabinetBuilder::new();
+        builder
+            .add_folder(cab::CompressionType::MsZip)
+            .add_file("ntdll.pdb");
+        let mut writer = builder.build(std::io::Cursor::new(Vec::new())).unwrap();
+        while let Some(mut w) = writer.next_file().unwrap() {
+            w.write_all(&pdb).unwrap();
+        }
+        let cabinet = writer.finish().unwrap().into_inner();
+
+        let url = serve(vec![(
+            "/ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81/ntdll.pd_",
+            cabinet,
+        )])
+        .await;
+        let cache = tempfile::tempdir().unwrap();
+
+        let srv =
+            SymSrv::connect(SymSrvSpec::test(ServerKind::SymStore, url, cache.path())).unwrap();
+
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(path.file_name().unwrap(), "ntdll.pdb");
+        assert_eq!(std::fs::read(&path).unwrap(), pdb);
+    }
+
+    #[tokio::test]
+    async fn verify_download() {
+        let url = serve(vec![
+            (
+                "/ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81/ntdll.pdb",
+                verify::test_pdb(0x1B3F8A6C2D4E5F60718293A4B5C6D7E8, 1),
+            ),
+            (
+                "/kernel32.pdb/2C3F8A6C2D4E5F60718293A4B5C6D7E81/kernel32.pdb",
+                verify::test_pdb(0x1B3F8A6C2D4E5F60718293A4B5C6D7E8, 1),
+            ),
+        ])
+        .await;
+        let cache = tempfile::tempdir().unwrap();
+
+        let srv = SymSrv::connect(SymSrvSpec::test(ServerKind::SymStore, url, cache.path()))
+            .unwrap()
+            .with_verification(true);
+
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(verify::pdb_hash(&path).unwrap(), info.to_string());
+
+        // A PDB served under the wrong key is kept aside rather than cached.
+        let info = SymFileInfo::RawHash("2C3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        assert!(matches!(
+            srv.download_file("kernel32.pdb", &info).await,
+            Err(DownloadError::SignatureMismatch { .. })
+        ));
+        assert!(srv.find_file("kernel32.pdb", &info).is_none());
+
+        let dir = cache
+            .path()
+            .join("kernel32.pdb/2C3F8A6C2D4E5F60718293A4B5C6D7E81");
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["kernel32.pdb.mismatch"]);
+    }
+
+    #[tokio::test]
+    async fn cache_chain() {
+        let url = serve(vec![(
+            "/ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81/ntdll.pdb",
+            b"downloaded".to_vec(),
+        )])
+        .await;
+        let local = tempfile::tempdir().unwrap();
+        let team = tempfile::tempdir().unwrap();
+
+        // Make the slower cache two-tier, to check each tier keeps its own layout.
+        std::fs::write(team.path().join("index2.txt"), "").unwrap();
+        let cached = team
+            .path()
+            .join("ke/kernel32.pdb/2C3F8A6C2D4E5F60718293A4B5C6D7E81");
+        std::fs::create_dir_all(&cached).unwrap();
+        std::fs::write(cached.join("kernel32.pdb"), b"cached").unwrap();
 
-        download_single(&self.client, &self.spec, Some(mp), name, &hash, use_two_tier)
+        let srv = SymSrv::connect(SymSrvSpec {
+            upstream_caches: vec![team.path().to_path_buf()],
+            ..SymSrvSpec::test(ServerKind::SymStore, url, local.path())
+        })
+        .unwrap();
+
+        // A hit in the slower cache is found, and copied into the local one.
+        let info = SymFileInfo::RawHash("2C3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        assert_eq!(
+            srv.find_file("kernel32.pdb", &info),
+            Some(cached.join("kernel32.pdb"))
+        );
+        let (status, path) = srv
+            .download_file_checked("kernel32.pdb", &info, &[])
             .await
-            .map(|r| r.1)
+            .unwrap();
+        assert_eq!(status, DownloadStatus::AlreadyExists);
+        assert!(path.starts_with(local.path()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"cached");
+        assert_eq!(srv.find_file("kernel32.pdb", &info), Some(path));
+
+        // A fresh download lands in every cache.
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        let (status, path) = srv
+            .download_file_checked("ntdll.pdb", &info, &[])
+            .await
+            .unwrap();
+        assert_eq!(status, DownloadStatus::DownloadedOk);
+        assert_eq!(std::fs::read(path).unwrap(), b"downloaded");
+        assert_eq!(
+            std::fs::read(
+                team.path()
+                    .join("nt/ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81/ntdll.pdb")
+            )
+            .unwrap(),
+            b"downloaded"
+        );
+    }
+
+    #[tokio::test]
+    async fn filesystem_store() {
+        let store = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+
+        std::fs::write(store.path().join("index2.txt"), "").unwrap();
+        let stored = store
+            .path()
+            .join("nt/ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81");
+        std::fs::create_dir_all(&stored).unwrap();
+        std::fs::write(stored.join("ntdll.pdb"), b"stored").unwrap();
+
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        let missing = SymFileInfo::RawHash("2C3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+
+        // With a separate cache, files are copied out of the store.
+        let srv = SymSrv::connect(SymSrvSpec::test(
+            ServerKind::Filesystem,
+            store.path().to_string_lossy().into_owned(),
+            cache.path(),
+        ))
+        .unwrap();
+
+        let mp = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let (status, path) = srv
+            .download_file_progress("ntdll.pdb", &info, &mp)
+            .await
+            .unwrap();
+        assert_eq!(status, DownloadStatus::DownloadedOk);
+        assert_eq!(
+            path,
+            cache
+                .path()
+                .join("ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81/ntdll.pdb")
+        );
+        assert_eq!(std::fs::read(path).unwrap(), b"stored");
+        assert!(matches!(
+            srv.download_file("ntdll.pdb", &missing).await,
+            Err(DownloadError::FileNotFound)
+        ));
+
+        // Made its own cache, the store is read in place.
+        let srv = SymSrv::connect(SymSrvSpec::test(
+            ServerKind::Filesystem,
+            store.path().to_string_lossy().into_owned(),
+            store.path(),
+        ))
+        .unwrap();
+
+        assert_eq!(
+            srv.find_file("ntdll.pdb", &info),
+            Some(stored.join("ntdll.pdb"))
+        );
+        assert!(srv.find_file("ntdll.pdb", &missing).is_none());
+    }
+
+    #[tokio::test]
+    async fn fileptr_redirection() {
+        let target = tempfile::tempdir().unwrap();
+        let pdb = target.path().join("ntdll.pdb");
+        std::fs::write(&pdb, b"redirected").unwrap();
+
+        let fileptr = format!("PATH:{}", pdb.display());
+        let url = serve(vec![
+            (
+                "/ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81/file.ptr",
+                fileptr.into_bytes(),
+            ),
+            (
+                "/ntdll.pdb/2C3F8A6C2D4E5F60718293A4B5C6D7E81/file.ptr",
+                b"MSG: Symbols archived".to_vec(),
+            ),
+            (
+                "/ntdll.pdb/3D3F8A6C2D4E5F60718293A4B5C6D7E81/file.ptr",
+                b"GOPHER:somewhere".to_vec(),
+            ),
+        ])
+        .await;
+        let cache = tempfile::tempdir().unwrap();
+
+        let srv =
+            SymSrv::connect(SymSrvSpec::test(ServerKind::SymStore, url, cache.path())).unwrap();
+
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"redirected");
+
+        let info = SymFileInfo::RawHash("2C3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        match srv.download_file("ntdll.pdb", &info).await {
+            Err(DownloadError::Message(msg)) => assert_eq!(msg, "Symbols archived"),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let info = SymFileInfo::RawHash("3D3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        assert!(matches!(
+            srv.download_file("ntdll.pdb", &info).await,
+            Err(DownloadError::InvalidFilePtr(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn authentication_failure() {
+        let url = serve_with(|req| {
+            if req.contains("/ntdll.pdb/") {
+                response(
+                    "200 OK",
+                    &[("Content-Type", "text/html; charset=utf-8")],
+                    b"<html>Sign in</html>",
+                )
+            } else if req.contains("/user32.pdb/") {
+                response(
+                    "403 Forbidden",
+                    &[("Content-Type", "text/html")],
+                    b"<html>Sign in</html>",
+                )
+            } else if req.contains("/gdi32.pdb/") {
+                // What an S3 bucket says about a missing file.
+                response(
+                    "403 Forbidden",
+                    &[("Content-Type", "application/xml")],
+                    b"<Error><Code>AccessDenied</Code></Error>",
+                )
+            } else {
+                response("401 Unauthorized", &[], b"")
+            }
+        })
+        .await;
+        let cache = tempfile::tempdir().unwrap();
+
+        let srv =
+            SymSrv::connect(SymSrvSpec::test(ServerKind::SymStore, url, cache.path())).unwrap();
+
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        for name in ["ntdll.pdb", "kernel32.pdb", "user32.pdb"] {
+            assert!(matches!(
+                srv.download_file(name, &info).await,
+                Err(DownloadError::Authentication { .. })
+            ));
+            assert!(srv.find_file(name, &info).is_none());
+        }
+
+        // A 403 alone is not taken as a credentials problem.
+        let res = srv.download_file("gdi32.pdb", &info).await;
+        assert!(res.is_err());
+        assert!(!matches!(res, Err(DownloadError::Authentication { .. })));
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+
+        for (retry, max) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (40, 1000),
+        ] {
+            let delay = policy.backoff(retry);
+            assert!(delay >= Duration::from_millis(max / 2));
+            assert!(delay <= Duration::from_millis(max));
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_transient_errors() {
+        use std::sync::atomic::AtomicUsize;
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let url = {
+            let attempts = attempts.clone();
+            serve_with(move |req| {
+                if req.starts_with("GET /kernel32.pdb/") {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    return response("503 Service Unavailable", &[], b"");
+                }
+                if !req.starts_with("GET /ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81/ntdll.pdb ") {
+                    return response("404 Not Found", &[], b"");
+                }
+
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => response("503 Service Unavailable", &[("Retry-After", "0")], b""),
+                    1 => response("429 Too Many Requests", &[], b""),
+                    _ => response("200 OK", &[], b"eventually"),
+                }
+            })
+            .await
+        };
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        };
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+
+        // Without retries, the first failure is final.
+        let cache = tempfile::tempdir().unwrap();
+        let srv = SymSrv::connect(SymSrvSpec::test(
+            ServerKind::SymStore,
+            url.clone(),
+            cache.path(),
+        ))
+        .unwrap()
+        .with_retry_policy(RetryPolicy::none());
+        assert!(srv.download_file("ntdll.pdb", &info).await.is_err());
+        assert_eq!(srv.retries(), 0);
+
+        attempts.store(0, Ordering::SeqCst);
+        let srv = srv.with_retry_policy(policy);
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"eventually");
+        assert_eq!(srv.retries(), 2);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // A server that keeps failing isn't then asked for the compressed file or file.ptr.
+        attempts.store(0, Ordering::SeqCst);
+        assert!(matches!(
+            srv.download_file("kernel32.pdb", &info).await,
+            Err(DownloadError::ServerError(
+                reqwest::StatusCode::SERVICE_UNAVAILABLE
+            ))
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn resume_download() {
+        let pdb = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0".repeat(64);
+        let url = {
+            let pdb = pdb.clone();
+            serve_with(move |req| {
+                let lower = req.to_ascii_lowercase();
+                let range = lower
+                    .lines()
+                    .find_map(|l| l.strip_prefix("range: bytes="))
+                    .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+                let (etag, body) = if req.contains("/ntdll.pdb/") {
+                    ("\"v1\"", pdb.clone())
+                } else {
+                    // This file changes between attempts.
+                    ("\"v2\"", b"changed".repeat(64))
+                };
+
+                match range {
+                    // Cut the first attempt short.
+                    None => {
+                        let mut res = response("200 OK", &[("ETag", "\"v1\"")], &body);
+                        res.truncate(res.len() - body.len() / 2);
+                        res
+                    }
+                    Some(start) if lower.contains(&format!("if-range: {etag}")) => response(
+                        "206 Partial Content",
+                        &[(
+                            "Content-Range",
+                            &format!("bytes {}-{}/{}", start, body.len() - 1, body.len()),
+                        )],
+                        &body[start..],
+                    ),
+                    Some(_) => response("200 OK", &[("ETag", etag)], &body),
+                }
+            })
+            .await
+        };
+        let cache = tempfile::tempdir().unwrap();
+
+        let srv = SymSrv::connect(SymSrvSpec::test(ServerKind::SymStore, url, cache.path()))
+            .unwrap()
+            .with_retry_policy(RetryPolicy::none());
+
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        assert!(srv.download_file("ntdll.pdb", &info).await.is_err());
+        let dir = cache
+            .path()
+            .join("ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81");
+        assert!(Partial::path(&dir.join("ntdll.pdb")).exists());
+
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), pdb);
+
+        // A changed validator restarts the download from scratch.
+        assert!(srv.download_file("kernel32.pdb", &info).await.is_err());
+        let path = srv.download_file("kernel32.pdb", &info).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"changed".repeat(64));
+
+        // Nothing is left behind once the download completes.
+        let files = std::fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(files, 1);
+
+        // With retries, a transfer cut off part-way is resumed straight away.
+        let srv = srv.with_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        });
+        let info = SymFileInfo::RawHash("2C3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), pdb);
+        assert_eq!(srv.retries(), 1);
+    }
+
+    #[tokio::test]
+    async fn resume_after_failed_attempt() {
+        use std::sync::atomic::AtomicUsize;
+
+        let pdb = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0".repeat(64);
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let url = {
+            let (pdb, attempts) = (pdb.clone(), attempts.clone());
+            serve_with(move |req| {
+                let lower = req.to_ascii_lowercase();
+                let range = lower
+                    .lines()
+                    .find_map(|l| l.strip_prefix("range: bytes="))
+                    .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+
+                match (attempts.fetch_add(1, Ordering::SeqCst), range) {
+                    // Cut the first attempt short, then fail the first attempt to resume.
+                    (0, _) => {
+                        let mut res = response("200 OK", &[("ETag", "\"v1\"")], &pdb);
+                        res.truncate(res.len() - pdb.len() / 2);
+                        res
+                    }
+                    (1, _) => response("503 Service Unavailable", &[], b""),
+                    (_, Some(start)) => response(
+                        "206 Partial Content",
+                        &[(
+                            "Content-Range",
+                            &format!("bytes {}-{}/{}", start, pdb.len() - 1, pdb.len()),
+                        )],
+                        &pdb[start..],
+                    ),
+                    (_, None) => response("500 Internal Server Error", &[], b""),
+                }
+            })
+            .await
+        };
+        let cache = tempfile::tempdir().unwrap();
+
+        let srv = SymSrv::connect(SymSrvSpec::test(ServerKind::SymStore, url, cache.path()))
+            .unwrap()
+            .with_retry_policy(RetryPolicy::none());
+
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+        let file_name = cache
+            .path()
+            .join("ntdll.pdb/1B3F8A6C2D4E5F60718293A4B5C6D7E81/ntdll.pdb");
+        for _ in 0..2 {
+            assert!(srv.download_file("ntdll.pdb", &info).await.is_err());
+            assert!(Partial::path(&file_name).exists());
+            assert!(Partial::validator_path(&file_name).exists());
+        }
+
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), pdb);
+    }
+
+    #[tokio::test]
+    async fn temp_file_cleanup() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = dir.path().join("ntdll.pdb");
+
+        let a = TempFile::new(&file_name);
+        let mut b = TempFile::new(&file_name);
+        assert_ne!(a.path(), b.path());
+
+        // Dropped files are removed, unless they can be resumed.
+        std::fs::write(a.path(), b"a").unwrap();
+        drop(a);
+        std::fs::write(b.path(), b"b").unwrap();
+        b.resume_to = Some(Partial::path(&file_name));
+        drop(b);
+        let files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["ntdll.pdb.partial"]);
+
+        let c = TempFile::new(&file_name);
+        std::fs::write(c.path(), b"c").unwrap();
+        c.persist(&file_name).await.unwrap();
+        assert_eq!(std::fs::read(&file_name).unwrap(), b"c");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn connection_limits() {
+        let spec = |connections| SymSrvSpec {
+            connections,
+            ..SymSrvSpec::test(ServerKind::SymStore, "https://symbols.example.com", "sym")
+        };
+        let jobs = Arc::new(Semaphore::new(2));
+        let busy = SymSrv::connect(spec(Some(1)))
+            .unwrap()
+            .with_job_limit(jobs.clone());
+        let other = SymSrv::connect(spec(None))
+            .unwrap()
+            .with_job_limit(jobs.clone());
+        let wait = Duration::from_millis(50);
+
+        // A server at its own limit waits without taking a shared slot...
+        let held = busy.acquire().await;
+        assert!(tokio::time::timeout(wait, busy.acquire()).await.is_err());
+        assert!(busy.try_acquire().is_none());
+        assert_eq!(jobs.available_permits(), 1);
+
+        // ...which leaves it for the other servers, up to the shared limit.
+        let other_held = other.try_acquire().unwrap();
+        assert!(tokio::time::timeout(wait, other.acquire()).await.is_err());
+        assert!(other.try_acquire().is_none());
+
+        // Slots are given back when dropped.
+        drop(held);
+        assert!(busy.try_acquire().is_some());
+        drop(other_held);
+        assert_eq!(jobs.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let limit = RateLimiter::new(10_000);
+        let start = Instant::now();
+
+        // A full bucket allows a burst of up to a second's worth...
+        limit.consume(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // ...after which every byte has to be waited for.
+        limit.consume(2_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn stalled_transfer() {
+        let pdb = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0".repeat(64);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let body = pdb.clone();
+        tokio::spawn(async move {
+            // Connections we stop answering, held open so the client sees a stall.
+            let mut stalled = Vec::new();
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = sock.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+
+                let req = String::from_utf8_lossy(&req).to_ascii_lowercase();
+                let range = req
+                    .lines()
+                    .find_map(|l| l.strip_prefix("range: bytes="))
+                    .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+                if !req.contains("/ntdll.pdb/") {
+                    // Never respond at all.
+                } else if let Some(start) = range {
+                    let range = format!("bytes {}-{}/{}", start, body.len() - 1, body.len());
+                    let res = response(
+                        "206 Partial Content",
+                        &[("Content-Range", &range)],
+                        &body[start..],
+                    );
+                    let _ = sock.write_all(&res).await;
+                    continue;
+                } else {
+                    // Send half the file, then go quiet.
+                    let mut res = response("200 OK", &[("ETag", "\"v1\"")], &body);
+                    res.truncate(res.len() - body.len() / 2);
+                    let _ = sock.write_all(&res).await;
+                }
+
+                stalled.push(sock);
+            }
+        });
+
+        let cache = tempfile::tempdir().unwrap();
+        let srv = SymSrv::connect(SymSrvSpec {
+            timeouts: Timeouts {
+                idle: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..SymSrvSpec::test(ServerKind::SymStore, url, cache.path())
+        })
+        .unwrap()
+        .with_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        });
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+
+        // The stalled transfer is abandoned, then resumed.
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), pdb);
+        assert_eq!(srv.retries(), 1);
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+
+        let srv = srv.with_retry_policy(RetryPolicy::none());
+        let err = srv.download_file("kernel32.pdb", &info).await.unwrap_err();
+        assert!(matches!(err, DownloadError::Stalled(_)));
+        assert!(err.is_retryable());
+        assert!(!cache.path().join("kernel32.pdb").exists());
+    }
+
+    #[tokio::test]
+    async fn shared_retry_budget() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicU64::new(0));
+
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            // Connections we stop answering, held open so the client sees a stall.
+            let mut stalled = Vec::new();
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = sock.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    req.extend_from_slice(&buf[..n]);
+                }
+
+                // Fail transiently, then stall, then succeed.
+                match counter.fetch_add(1, Ordering::SeqCst) % 3 {
+                    0 => {
+                        let res = response("503 Service Unavailable", &[], b"");
+                        let _ = sock.write_all(&res).await;
+                    }
+                    1 => stalled.push(sock),
+                    _ => {
+                        let res = response("200 OK", &[], b"eventually");
+                        let _ = sock.write_all(&res).await;
+                    }
+                }
+            }
+        });
+
+        let cache = tempfile::tempdir().unwrap();
+        let srv = SymSrv::connect(SymSrvSpec {
+            timeouts: Timeouts {
+                idle: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..SymSrvSpec::test(ServerKind::SymStore, url, cache.path())
+        })
+        .unwrap();
+        let policy = |max_attempts| RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        };
+        let info = SymFileInfo::RawHash("1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string());
+
+        // Retrying the 503 uses up the budget, so the stall isn't retried as well.
+        let srv = srv.with_retry_policy(policy(2));
+        let err = srv.download_file("ntdll.pdb", &info).await.unwrap_err();
+        assert!(matches!(err, DownloadError::Stalled(_)));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(srv.retries(), 1);
+
+        requests.store(0, Ordering::SeqCst);
+        let srv = srv.with_retry_policy(policy(3));
+        let path = srv.download_file("ntdll.pdb", &info).await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"eventually");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(srv.retries(), 3);
     }
 }
@@ -0,0 +1,397 @@
+//! Parsing of symbol paths in the `_NT_SYMBOL_PATH` grammar understood by
+//! WinDbg, e.g. `cache*C:\Symcache;SRV*https://msdl.microsoft.com/download/symbols`.
+//!
+//! Reference: https://learn.microsoft.com/en-us/windows-hardware/drivers/debugger/symbol-path
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::Context;
+
+use super::{ServerKind, SymSrvSpec, Timeouts};
+
+/// The symbol server used by a bare `srv*`.
+pub const DEFAULT_SERVER: &str = "https://msdl.microsoft.com/download/symbols";
+
+/// The downstream store used when a symbol path asks for the default one
+/// (e.g. `srv**<server>` or `cache*`), or doesn't name one at all.
+pub const DEFAULT_DOWNSTREAM_STORE: &str = "sym";
+
+/// Determine whether a symbol store is a URL rather than a directory.
+fn is_url(store: &str) -> bool {
+    let store = store.to_ascii_lowercase();
+    store.starts_with("http://") || store.starts_with("https://")
+}
+
+/// Parse a transfer rate in bytes per second, with an optional `K`, `M` or `G`
+/// suffix for multiples of 1024, e.g. `512K`.
+pub fn parse_rate(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|&n| n != 0)
+        .with_context(|| format!("Invalid rate \"{s}\"; expected e.g. 512K or 2M"))
+}
+
+/// A single `;`-separated element of a symbol path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymPathElement {
+    /// `srv*[<cache>*...]<server>`, or the equivalent `symsrv*symsrv.dll*...`.
+    Server {
+        kind: ServerKind,
+        /// The downstream stores, nearest first. Empty entries in the symbol
+        /// path have been replaced with [`DEFAULT_DOWNSTREAM_STORE`].
+        caches: Vec<PathBuf>,
+        /// The upstream store, e.g. `https://msdl.microsoft.com/download/symbols`.
+        server: String,
+        /// The concurrent download limit given with a trailing `*connections=<N>`.
+        connections: Option<usize>,
+        /// The bandwidth limit in bytes per second given with a trailing `*rate=<N>`.
+        rate_limit: Option<u64>,
+        /// The timeouts, as changed by any trailing `*<connect_|idle_>timeout=<secs>`.
+        timeouts: Timeouts,
+    },
+    /// `cache*[<dir>]`, which caches everything from the elements after it.
+    Cache(PathBuf),
+    /// A plain directory or UNC share, used as a [`ServerKind::Filesystem`]
+    /// store.
+    Directory(PathBuf),
+}
+
+impl FromStr for SymPathElement {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let directives: Vec<&str> = s.split('*').collect();
+        let store = |s: &&str| {
+            if s.is_empty() {
+                PathBuf::from(DEFAULT_DOWNSTREAM_STORE)
+            } else {
+                PathBuf::from(s)
+            }
+        };
+
+        let (kind, stores) = match directives[0] {
+            _ if directives.len() == 1 => return Ok(SymPathElement::Directory(s.into())),
+            x if x.eq_ignore_ascii_case("cache") => {
+                if directives.len() != 2 {
+                    anyhow::bail!("Invalid cache element \"{s}\"; expected 'cache*[<CACHE_PATH>]'");
+                }
+
+                return Ok(SymPathElement::Cache(store(&directives[1])));
+            }
+            x if x.eq_ignore_ascii_case("srv") => (ServerKind::SymStore, &directives[1..]),
+            x if x.eq_ignore_ascii_case("symsrv") => {
+                // N.B: The second directive names the DLL implementing the store, which is us.
+                if directives.len() < 3 {
+                    anyhow::bail!(
+                        "Invalid symsrv element \"{s}\"; expected 'symsrv*<DLL>*<SYMBOL_SERVER>'"
+                    );
+                }
+
+                (ServerKind::SymStore, &directives[2..])
+            }
+            x if x.eq_ignore_ascii_case("debuginfod") => (ServerKind::Debuginfod, &directives[1..]),
+            x => anyhow::bail!("Unsupported symbol path element type \"{x}\" in \"{s}\""),
+        };
+
+        // Trailing `key=value` directives are options for the server, not stores.
+        let mut stores = stores;
+        let mut connections = None;
+        let mut rate_limit = None;
+        let mut timeouts = Timeouts::default();
+        while let Some((option, rest)) = stores.split_last() {
+            let secs = |value: &str| {
+                value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&n| n != 0)
+                    .map(Duration::from_secs)
+                    .with_context(|| format!("Invalid timeout in \"{s}\""))
+            };
+
+            match option.split_once('=') {
+                Some((key, value)) if key.eq_ignore_ascii_case("connect_timeout") => {
+                    timeouts.connect = secs(value)?;
+                }
+                Some((key, value)) if key.eq_ignore_ascii_case("idle_timeout") => {
+                    timeouts.idle = secs(value)?;
+                }
+                Some((key, value)) if key.eq_ignore_ascii_case("timeout") => {
+                    timeouts.overall = Some(secs(value)?);
+                }
+                Some((key, value)) if key.eq_ignore_ascii_case("rate") => {
+                    rate_limit = Some(
+                        parse_rate(value)
+                            .with_context(|| format!("Invalid rate limit in \"{s}\""))?,
+                    );
+                }
+                Some((key, value)) if key.eq_ignore_ascii_case("connections") => {
+                    let value = value
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&n| n != 0)
+                        .with_context(|| format!("Invalid connection limit in \"{s}\""))?;
+                    connections = Some(value);
+                }
+                _ => break,
+            }
+
+            stores = rest;
+        }
+
+        let (server, caches) = stores.split_last().unwrap_or((&"", &[]));
+        let (kind, server) = match (*server, kind) {
+            ("", ServerKind::Debuginfod) => anyhow::bail!("No debuginfod server in \"{s}\""),
+            ("", kind) => (kind, DEFAULT_SERVER.to_string()),
+            // Anything that isn't a URL is a store on the filesystem, e.g. `\\server\symbols`.
+            (server, ServerKind::SymStore) if !is_url(server) => {
+                (ServerKind::Filesystem, server.to_string())
+            }
+            (server, kind) => (kind, server.to_string()),
+        };
+
+        Ok(SymPathElement::Server {
+            kind,
+            caches: caches.iter().map(store).collect(),
+            server,
+            connections,
+            rate_limit,
+            timeouts,
+        })
+    }
+}
+
+/// A symbol path, as found in `_NT_SYMBOL_PATH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolPath(pub Vec<SymPathElement>);
+
+impl FromStr for SymbolPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let elements = s
+            .split(';')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(SymPathElement::from_str)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(SymbolPath(elements))
+    }
+}
+
+impl SymbolPath {
+    /// Resolve the symbol servers in this path, in the order they should be
+    /// consulted, along with the cache each one downloads into.
+    ///
+    /// The nearest preceding `cache*` element is the first cache of every
+    /// server after it, ahead of the server's own downstream stores. Servers
+    /// with no caches at all, plain directories included, use
+    /// [`DEFAULT_DOWNSTREAM_STORE`], so a store is never written to.
+    pub fn servers(&self) -> anyhow::Result<Vec<SymSrvSpec>> {
+        let mut cache = None;
+        let mut servers = Vec::new();
+
+        for element in &self.0 {
+            match element {
+                SymPathElement::Cache(path) => cache = Some(path.clone()),
+                SymPathElement::Directory(path) => servers.push(SymSrvSpec {
+                    kind: ServerKind::Filesystem,
+                    server_url: path.to_string_lossy().into_owned(),
+                    cache_path: cache
+                        .clone()
+                        .unwrap_or_else(|| PathBuf::from(DEFAULT_DOWNSTREAM_STORE)),
+                    upstream_caches: vec![],
+                    connections: None,
+                    rate_limit: None,
+                    timeouts: Timeouts::default(),
+                }),
+                SymPathElement::Server {
+                    kind,
+                    caches,
+                    server,
+                    connections,
+                    rate_limit,
+                    timeouts,
+                } => {
+                    let mut tiers = cache.iter().chain(caches.iter()).cloned();
+                    let cache_path = tiers
+                        .next()
+                        .unwrap_or_else(|| PathBuf::from(DEFAULT_DOWNSTREAM_STORE));
+
+                    servers.push(SymSrvSpec {
+                        kind: *kind,
+                        server_url: server.clone(),
+                        cache_path,
+                        upstream_caches: tiers.collect(),
+                        connections: *connections,
+                        rate_limit: *rate_limit,
+                        timeouts: *timeouts,
+                    });
+                }
+            }
+        }
+
+        Ok(servers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn server(kind: ServerKind, caches: &[&str], server: &str) -> SymPathElement {
+        SymPathElement::Server {
+            kind,
+            caches: caches.iter().map(PathBuf::from).collect(),
+            server: server.to_string(),
+            connections: None,
+            rate_limit: None,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    #[test]
+    fn parse_elements() {
+        let path = SymbolPath::from_str(
+            "cache*C:\\Symcache; SRV*https://symbols.example.com;srv**\\\\server\\symbols;\
+             symsrv*symsrv.dll*C:\\Local*C:\\Team*https://msdl.microsoft.com/download/symbols;\
+             srv*;C:\\Build\\Symbols;cache*;DEBUGINFOD*/var/cache/sym*https://debuginfod.elfutils.org",
+        )
+        .unwrap();
+
+        assert_eq!(
+            path.0,
+            vec![
+                SymPathElement::Cache("C:\\Symcache".into()),
+                server(ServerKind::SymStore, &[], "https://symbols.example.com"),
+                server(ServerKind::Filesystem, &["sym"], "\\\\server\\symbols"),
+                server(
+                    ServerKind::SymStore,
+                    &["C:\\Local", "C:\\Team"],
+                    "https://msdl.microsoft.com/download/symbols"
+                ),
+                server(ServerKind::SymStore, &[], DEFAULT_SERVER),
+                SymPathElement::Directory("C:\\Build\\Symbols".into()),
+                SymPathElement::Cache("sym".into()),
+                server(
+                    ServerKind::Debuginfod,
+                    &["/var/cache/sym"],
+                    "https://debuginfod.elfutils.org"
+                ),
+            ]
+        );
+
+        let path = SymbolPath::from_str(
+            "srv*C:\\Symcache*https://symbols.example.com*Connections=8*rate=2M*idle_timeout=15*timeout=600",
+        )
+        .unwrap();
+        assert_eq!(
+            path.0,
+            vec![SymPathElement::Server {
+                kind: ServerKind::SymStore,
+                caches: vec!["C:\\Symcache".into()],
+                server: "https://symbols.example.com".to_string(),
+                connections: Some(8),
+                rate_limit: Some(2 * 1024 * 1024),
+                timeouts: Timeouts {
+                    idle: Duration::from_secs(15),
+                    overall: Some(Duration::from_secs(600)),
+                    ..Default::default()
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(parse_rate("4096").unwrap(), 4096);
+        assert_eq!(parse_rate("512k").unwrap(), 512 * 1024);
+        assert_eq!(parse_rate("2M").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate("1G").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("M").is_err());
+        assert!(parse_rate("2T").is_err());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(SymbolPath::from_str("http*https://symbols.example.com").is_err());
+        assert!(SymbolPath::from_str("cache*C:\\a*C:\\b").is_err());
+        assert!(SymbolPath::from_str("symsrv*symsrv.dll").is_err());
+        assert!(SymbolPath::from_str("debuginfod*").is_err());
+        assert!(SymbolPath::from_str("srv*https://symbols.example.com*connections=0").is_err());
+        assert!(SymbolPath::from_str("srv*https://symbols.example.com*connections=").is_err());
+        assert!(SymbolPath::from_str("srv*https://symbols.example.com*rate=fast").is_err());
+        assert!(SymbolPath::from_str("srv*https://symbols.example.com*timeout=0").is_err());
+        assert_eq!(SymbolPath::from_str(" ; ").unwrap().0, vec![]);
+    }
+
+    #[test]
+    fn resolve_servers() {
+        let servers = SymbolPath::from_str(
+            "srv*https://a.example.com;cache*C:\\Symcache;srv*https://b.example.com;\
+             srv*C:\\Local*\\\\team\\symbols*https://c.example.com",
+        )
+        .unwrap()
+        .servers()
+        .unwrap();
+
+        assert_eq!(
+            servers,
+            vec![
+                SymSrvSpec::test(
+                    ServerKind::SymStore,
+                    "https://a.example.com",
+                    DEFAULT_DOWNSTREAM_STORE
+                ),
+                SymSrvSpec::test(
+                    ServerKind::SymStore,
+                    "https://b.example.com",
+                    "C:\\Symcache"
+                ),
+                SymSrvSpec {
+                    upstream_caches: vec!["C:\\Local".into(), "\\\\team\\symbols".into()],
+                    ..SymSrvSpec::test(
+                        ServerKind::SymStore,
+                        "https://c.example.com",
+                        "C:\\Symcache"
+                    )
+                },
+            ]
+        );
+
+        let servers = SymbolPath::from_str("/mnt/symbols;cache*/var/cache/sym;/mnt/build")
+            .unwrap()
+            .servers()
+            .unwrap();
+        assert_eq!(
+            servers,
+            vec![
+                SymSrvSpec::test(
+                    ServerKind::Filesystem,
+                    "/mnt/symbols",
+                    DEFAULT_DOWNSTREAM_STORE
+                ),
+                SymSrvSpec::test(ServerKind::Filesystem, "/mnt/build", "/var/cache/sym"),
+            ]
+        );
+        assert!(servers.iter().all(SymSrvSpec::has_cache));
+
+        // A store is only written to if it is explicitly made its own cache.
+        let servers = SymbolPath::from_str("cache*/mnt/symbols;/mnt/symbols")
+            .unwrap()
+            .servers()
+            .unwrap();
+        assert_eq!(servers[0].cache_path, PathBuf::from("/mnt/symbols"));
+        assert!(!servers[0].has_cache());
+    }
+}
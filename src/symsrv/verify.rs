@@ -0,0 +1,186 @@
+//! Verification that a PDB on disk is the one a symbol server key refers to.
+use std::path::Path;
+
+use anyhow::Context;
+
+/// The magic at the start of an MSF 7.0 PDB.
+const MSF7_MAGIC: &[u8] = b"Microsoft C/C++ MSF 7.00\r\n\x1aDS\0\0\0";
+
+/// The outcome of checking a file against its symbol server key.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// The file's signature matches the key.
+    Match,
+    /// The file is a PDB with a different signature.
+    Mismatch { actual: String },
+    /// The file claims to be a PDB but could not be parsed.
+    Corrupt(String),
+    /// The file is not a format whose signature we can check (e.g. an
+    /// executable or a portable PDB).
+    Unsupported,
+}
+
+/// Read the symbol server key (`<GUID><age>`) of the PDB at `path`.
+pub fn pdb_hash(path: &Path) -> anyhow::Result<String> {
+    use pdb::PDB;
+
+    let f = std::fs::File::open(path).context("failed to open file")?;
+    let mut pdb = PDB::open(f).context("failed to parse PDB")?;
+
+    // Query the GUID and age.
+    let pdbi = pdb
+        .pdb_information()
+        .context("failed to find PDB information stream")?;
+    let dbi = pdb
+        .debug_information()
+        .context("failed to find DBI stream")?;
+
+    let guid = pdbi.guid;
+    let age = dbi.age().unwrap_or(pdbi.age);
+
+    Ok(format!("{:032X}{:x}", guid.as_u128(), age))
+}
+
+/// Check the file at `path` against the symbol server key `hash`, i.e. the
+/// middle component of its path in a symbol store.
+pub fn verify_pdb(path: &Path, hash: &str) -> anyhow::Result<Verification> {
+    use std::io::Read;
+
+    let mut magic = [0u8; MSF7_MAGIC.len()];
+    let mut f = std::fs::File::open(path).context("failed to open file")?;
+    if f.read_exact(&mut magic).is_err() || magic != MSF7_MAGIC {
+        return Ok(Verification::Unsupported);
+    }
+
+    Ok(match pdb_hash(path) {
+        Ok(actual) if actual.eq_ignore_ascii_case(hash) => Verification::Match,
+        Ok(actual) => Verification::Mismatch { actual },
+        Err(e) => Verification::Corrupt(format!("{e:#}")),
+    })
+}
+
+/// Build a minimal MSF 7.0 PDB whose symbol server key is `<guid><age>`.
+///
+/// Only the PDB information and DBI streams are present, which is all
+/// [`pdb_hash`] reads.
+#[cfg(test)]
+pub fn test_pdb(guid: u128, age: u32) -> Vec<u8> {
+    const PAGE_SIZE: usize = 4096;
+
+    // The PDB information stream: version, signature, age, GUID and an empty
+    // name table.
+    let mut pdbi = Vec::new();
+    pdbi.extend_from_slice(&20000404u32.to_le_bytes());
+    pdbi.extend_from_slice(&0u32.to_le_bytes());
+    pdbi.extend_from_slice(&age.to_le_bytes());
+    pdbi.extend_from_slice(&((guid >> 96) as u32).to_le_bytes());
+    pdbi.extend_from_slice(&((guid >> 80) as u16).to_le_bytes());
+    pdbi.extend_from_slice(&((guid >> 64) as u16).to_le_bytes());
+    pdbi.extend_from_slice(&(guid as u64).to_be_bytes());
+    pdbi.extend_from_slice(&0u32.to_le_bytes());
+
+    // The DBI stream header, with no substreams.
+    let mut dbi = [0u8; 64];
+    dbi[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    dbi[4..8].copy_from_slice(&19990903u32.to_le_bytes());
+    dbi[8..12].copy_from_slice(&age.to_le_bytes());
+
+    // The stream directory: four streams, with the PDB information in page 5
+    // and the DBI in page 6.
+    let mut directory = Vec::new();
+    for n in [4, 0, pdbi.len() as u32, 0, dbi.len() as u32, 5, 6] {
+        directory.extend_from_slice(&n.to_le_bytes());
+    }
+
+    // Page 0 is the superblock, pages 1 and 2 the free page maps, page 3 lists
+    // the pages of the directory and page 4 holds it.
+    let mut header = MSF7_MAGIC.to_vec();
+    for n in [PAGE_SIZE as u32, 1, 7, directory.len() as u32, 0, 3] {
+        header.extend_from_slice(&n.to_le_bytes());
+    }
+
+    let mut file = vec![0u8; 7 * PAGE_SIZE];
+    for (page, data) in [
+        (0, &header[..]),
+        (3, &4u32.to_le_bytes()[..]),
+        (4, &directory[..]),
+        (5, &pdbi[..]),
+        (6, &dbi[..]),
+    ] {
+        file[page * PAGE_SIZE..][..data.len()].copy_from_slice(data);
+    }
+
+    file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_match() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let pdb = dir.path().join("ntdll.pdb");
+        std::fs::write(&pdb, test_pdb(0x1B3F8A6C2D4E5F60718293A4B5C6D7E8, 0x1a)).unwrap();
+        assert_eq!(
+            pdb_hash(&pdb).unwrap(),
+            "1B3F8A6C2D4E5F60718293A4B5C6D7E81a"
+        );
+        assert_eq!(
+            verify_pdb(&pdb, "1B3F8A6C2D4E5F60718293A4B5C6D7E81A").unwrap(),
+            Verification::Match
+        );
+    }
+
+    #[test]
+    fn verify_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let pdb = dir.path().join("ntdll.pdb");
+        std::fs::write(&pdb, test_pdb(0x1B3F8A6C2D4E5F60718293A4B5C6D7E8, 1)).unwrap();
+
+        // Another build of the same PDB, and the same build at another age.
+        assert_eq!(
+            verify_pdb(&pdb, "2C3F8A6C2D4E5F60718293A4B5C6D7E81").unwrap(),
+            Verification::Mismatch {
+                actual: "1B3F8A6C2D4E5F60718293A4B5C6D7E81".to_string()
+            }
+        );
+        assert!(matches!(
+            verify_pdb(&pdb, "1B3F8A6C2D4E5F60718293A4B5C6D7E82").unwrap(),
+            Verification::Mismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn verify_unsupported() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let exe = dir.path().join("notepad.exe");
+        std::fs::write(&exe, b"MZ\x90\x00").unwrap();
+        assert_eq!(
+            verify_pdb(&exe, "5E8A1D2C3000").unwrap(),
+            Verification::Unsupported
+        );
+
+        let empty = dir.path().join("empty.pdb");
+        std::fs::write(&empty, b"").unwrap();
+        assert_eq!(
+            verify_pdb(&empty, "00000000000000000000000000000000").unwrap(),
+            Verification::Unsupported
+        );
+    }
+
+    #[test]
+    fn verify_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let pdb = dir.path().join("ntdll.pdb");
+        std::fs::write(&pdb, [MSF7_MAGIC, &[0xff; 64]].concat()).unwrap();
+        assert!(matches!(
+            verify_pdb(&pdb, "00000000000000000000000000000000").unwrap(),
+            Verification::Corrupt(_)
+        ));
+    }
+}